@@ -49,6 +49,8 @@ struct RpcMethod {
     ident: Ident,
     args: Vec<PatType>,
     output: ReturnType,
+    /// The retry policy of the `#[retry(...)]` attribute.
+    retry: Option<TokenStream2>,
 }
 
 impl Parse for Service {
@@ -83,7 +85,11 @@ impl Parse for Service {
 
 impl Parse for RpcMethod {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        let attrs = input.call(Attribute::parse_outer)?;
+        let (retry_attrs, attrs): (Vec<_>, Vec<_>) = input.call(Attribute::parse_outer)?.into_iter().partition(|attr| attr.path().is_ident("retry"));
+        if let Some(attr) = retry_attrs.get(1) {
+            return Err(syn::Error::new(attr.span(), "`retry` appears more than once"));
+        }
+        let retry = retry_attrs.first().map(parse_retry).transpose()?;
         input.parse::<Token![async]>()?;
         input.parse::<Token![fn]>()?;
         let ident = input.parse()?;
@@ -108,10 +114,52 @@ impl Parse for RpcMethod {
         let output = input.parse()?;
         input.parse::<Token![;]>()?;
 
-        Ok(Self { attrs, ident, args, output })
+        Ok(Self {
+            attrs,
+            ident,
+            args,
+            output,
+            retry,
+        })
     }
 }
 
+/// Parses the `#[retry(...)]` attribute of a method into the expression of its retry policy.
+fn parse_retry(attr: &Attribute) -> syn::Result<TokenStream2> {
+    let mut policy = quote! { ::logimesh::client::lrcall::RetryPolicy::new() };
+    attr.parse_nested_meta(|meta| {
+        let Some(ident) = meta.path.get_ident() else {
+            return Err(meta.error("unsupported retry option"));
+        };
+        let option = ident.to_string();
+        match option.as_str() {
+            "idempotent" => policy.extend(quote! { .with_idempotent(true) }),
+            "max_attempts" => {
+                let value: syn::LitInt = meta.value()?.parse()?;
+                policy.extend(quote! { .with_max_attempts(#value) });
+            },
+            "initial_backoff_ms" | "max_backoff_ms" | "max_retry_time_ms" => {
+                let value: syn::LitInt = meta.value()?.parse()?;
+                let method = format_ident!("with_{}", option.trim_end_matches("_ms"));
+                policy.extend(quote! { .#method(::core::time::Duration::from_millis(#value)) });
+            },
+            "multiplier" | "jitter" => {
+                let value: syn::LitFloat = meta.value()?.parse()?;
+                let method = format_ident!("with_{}", option);
+                policy.extend(quote! { .#method(#value) });
+            },
+            "retry_on" => {
+                let kinds: syn::ExprArray = meta.value()?.parse()?;
+                let kinds = kinds.elems.iter();
+                policy.extend(quote! { .with_retry_on(&[#( ::logimesh::client::lrcall::RpcErrorKind::#kinds ),*]) });
+            },
+            _ => return Err(meta.error("unsupported retry option, expected `max_attempts`, `initial_backoff_ms`, `max_backoff_ms`, `max_retry_time_ms`, `multiplier`, `jitter`, `retry_on` or `idempotent`")),
+        }
+        Ok(())
+    })?;
+    Ok(policy)
+}
+
 #[derive(Default)]
 struct DeriveMeta {
     derive: Option<Derive>,
@@ -376,6 +424,23 @@ pub fn component(attr: TokenStream, input: TokenStream) -> TokenStream {
         },
     };
 
+    // The request always derives `Clone`, so that only the response derives it when asked.
+    let request_derives = match derive_meta.derive.as_ref() {
+        Some(Derive::Explicit(paths)) if paths.iter().any(|path| path.is_ident("Clone")) => {
+            let paths = paths.iter().filter(|path| !path.is_ident("Clone"));
+            Some(quote! {
+                #[derive(
+                    #(
+                        #paths
+                    ),*
+                )]
+                #[derive(::logimesh::serde::Serialize, ::logimesh::serde::Deserialize)]
+                #[serde(crate = "::logimesh::serde")]
+            })
+        },
+        _ => derives.clone(),
+    };
+
     let methods = rpcs.iter().map(|rpc| &rpc.ident).collect::<Vec<_>>();
     let request_names = methods.iter().map(|m| format!("{ident}.{m}")).collect::<Vec<_>>();
 
@@ -407,6 +472,7 @@ pub fn component(attr: TokenStream, input: TokenStream) -> TokenStream {
         arg_pats: &args.iter().map(|args| args.iter().map(|arg| &*arg.pat).collect()).collect::<Vec<_>>(),
         camel_case_idents: &rpcs.iter().zip(camel_case_fn_names.iter()).map(|(rpc, name)| Ident::new(name, rpc.ident.span())).collect::<Vec<_>>(),
         derives: derives.as_ref(),
+        request_derives: request_derives.as_ref(),
         warnings: &derive_meta.warnings,
     }
     .into_token_stream();
@@ -440,6 +506,7 @@ struct ServiceGenerator<'a> {
     return_types: &'a [&'a Type],
     arg_pats: &'a [Vec<&'a Pat>],
     derives: Option<&'a TokenStream2>,
+    request_derives: Option<&'a TokenStream2>,
     warnings: &'a [TokenStream2],
 }
 
@@ -459,9 +526,16 @@ impl<'a> ServiceGenerator<'a> {
             request_ident,
             response_ident,
             server_ident,
+            camel_case_idents,
+            method_cfgs,
             ..
         } = self;
 
+        let retry_policies = rpcs.iter().map(|rpc| match &rpc.retry {
+            Some(policy) => quote! { ::core::option::Option::Some(#policy) },
+            None => quote! { ::core::option::Option::None },
+        });
+
         let rpc_fns = rpcs.iter().zip(return_types.iter()).map(|(RpcMethod { attrs, ident, args, .. }, output)| {
             quote! {
                 #( #attrs )*
@@ -479,6 +553,7 @@ impl<'a> ServiceGenerator<'a> {
             }
         });
 
+        let unimplemented_doc = format!(r" An implementation of [`{service_ident}`] whose methods are all unimplemented.");
         let stub_doc = format!(r" The stub trait for service [`{service_ident}`].");
         let channel_doc1 = format!(r" The default {client_stub_ident} implementation.");
         let channel_doc2 = format!(r" Usage: `{channel_ident}::spawn(config, transport)`");
@@ -522,6 +597,8 @@ impl<'a> ServiceGenerator<'a> {
                         .with_config_ext(config_ext)
                         .with_transport_codec(Self::TRANSPORT_CODEC)
                         .with_retry_fn(Self::logimesh_should_retry)
+                        .with_retry_policy_fn(Self::logimesh_retry_policy)
+                        .with_fallback_fn(Self::logimesh_should_fallback)
                         .try_spawn()
                         .await?,
                     )))
@@ -535,6 +612,26 @@ impl<'a> ServiceGenerator<'a> {
                     false
                 }
 
+                /// Returns the retry policy of the remote calls of the request, which is set by the `#[retry(...)]` attribute
+                /// of its method. The remote calls of the methods without it are retried by `logimesh_should_retry`.
+                #[allow(unused_variables)]
+                fn logimesh_retry_policy(request: &#request_ident) -> ::core::option::Option<::logimesh::client::lrcall::RetryPolicy> {
+                    match request {
+                        #(
+                            #( #method_cfgs )*
+                            #request_ident::#camel_case_idents{..} => #retry_policies,
+                        )*
+                    }
+                }
+
+                /// Judge whether a failed remote call should fall back to the local call, once the retries are exhausted.
+                /// The request tells the method, and the error tells the kind of failure, e.g. `RpcError::Shutdown`.
+                /// The default never falls back, so you should implement your own version to opt in.
+                #[allow(unused_variables)]
+                fn logimesh_should_fallback(request: &#request_ident, error: &::logimesh::client::core::RpcError) -> bool {
+                    false
+                }
+
                 /// Returns the Self::TRANSPORT_CODEC.
                 /// NOTE: Implementation is not allowed to be overridden.
                 /// If you need to modify the encoder, Self::TRANSPORT_CODEC should be specified.
@@ -543,6 +640,7 @@ impl<'a> ServiceGenerator<'a> {
                 }
             }
 
+            #[doc = #unimplemented_doc]
             #[derive(Debug,Clone,Copy)]
             #vis struct #service_unimplemented_ident;
 
@@ -621,7 +719,7 @@ impl<'a> ServiceGenerator<'a> {
 
     fn enum_request(&self) -> TokenStream2 {
         let &Self {
-            derives,
+            request_derives,
             vis,
             request_ident,
             camel_case_idents,
@@ -635,7 +733,7 @@ impl<'a> ServiceGenerator<'a> {
             /// The request sent over the wire from the client to the server.
             #[allow(missing_docs)]
             #[derive(Debug, Clone)]
-            #derives
+            #request_derives
             #vis enum #request_ident {
                 #(
                     #( #method_cfgs )*
@@ -808,6 +906,18 @@ impl<'a> ServiceGenerator<'a> {
                     &self.0
                 }
             }
+
+            impl<S, D, LB> #newtype_lrclient_ident<S, D, LB>
+            where
+                S: #service_ident,
+                D: ::logimesh::client::discover::Discover,
+                LB: ::logimesh::client::balance::LoadBalance<#server_ident<S>>,
+            {
+                /// Returns a cloneable handle to pin the client to local or remote calls at runtime.
+                pub fn logimesh_control(&self) -> ::logimesh::client::lrcall::LRControl {
+                    self.0.0.control()
+                }
+            }
         }
     }
 
@@ -101,6 +101,58 @@ fn custom_derives() {
     requires_hash(x);
 }
 
+#[test]
+fn derive_clone_response() {
+    #[logimesh::component(derive = [Clone])]
+    trait Foo {
+        async fn foo();
+    }
+
+    fn requires_clone(_: impl Clone) {}
+
+    requires_clone(FooRequest::Foo {});
+    requires_clone(FooResponse::Foo(()));
+}
+
+#[test]
+fn retry_attributes() {
+    use logimesh::client::lrcall::{RetryPolicy, RpcErrorKind};
+    use std::time::Duration;
+
+    #[logimesh::component]
+    trait Foo {
+        #[retry(max_attempts = 5, initial_backoff_ms = 20, max_retry_time_ms = 1000, jitter = 0.1, retry_on = [Shutdown, DeadlineExceeded], idempotent)]
+        async fn get();
+        #[retry(max_attempts = 2)]
+        async fn set();
+        async fn other();
+    }
+
+    #[derive(Clone)]
+    struct Server;
+
+    impl Foo for Server {
+        async fn get(self, _: context::Context) {}
+        async fn set(self, _: context::Context) {}
+        async fn other(self, _: context::Context) {}
+    }
+
+    assert_eq!(
+        Server::logimesh_retry_policy(&FooRequest::Get {}),
+        Some(
+            RetryPolicy::new()
+                .with_max_attempts(5)
+                .with_initial_backoff(Duration::from_millis(20))
+                .with_max_retry_time(Duration::from_millis(1000))
+                .with_jitter(0.1)
+                .with_retry_on(&[RpcErrorKind::Shutdown, RpcErrorKind::DeadlineExceeded])
+                .with_idempotent(true)
+        )
+    );
+    assert_eq!(Server::logimesh_retry_policy(&FooRequest::Set {}), Some(RetryPolicy::new().with_max_attempts(2)));
+    assert_eq!(Server::logimesh_retry_policy(&FooRequest::Other {}), None);
+}
+
 #[test]
 fn implicit_serde() {
     #[logimesh::component]
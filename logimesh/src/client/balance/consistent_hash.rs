@@ -0,0 +1,312 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Consistent hash load balance implemention
+
+use super::{LoadBalance, RpcChange};
+use crate::client::channel::RpcChannel;
+use crate::context::Context;
+use crate::net::Address;
+use crate::server::Serve;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::ops::Bound;
+use std::sync::{Arc, RwLock};
+
+/// Default number of points an instance of weight 1 has on the ring.
+const DEFAULT_REPLICAS: u32 = 100;
+
+/// Hashes a request key, e.g. a user id, for [`ConsistentHashBalance`].
+///
+/// The hash is FNV-1a with a final mix, which is fixed across Rust releases, processes and platforms,
+/// so that all the clients of a service map the keys to the same instances.
+pub fn hash_key(key: impl Hash) -> u64 {
+    let mut hasher = StableHasher(FNV_OFFSET_BASIS);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// A [`Hasher`] whose output does not depend on the Rust release or the platform, unlike
+/// [`std::collections::hash_map::DefaultHasher`], the integers are written in little endian.
+struct StableHasher(u64);
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        // The finalizer of MurmurHash3, which spreads the points of similar keys over the ring.
+        let mut h = self.0;
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        h ^= h >> 33;
+        h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+        h ^ (h >> 33)
+    }
+}
+
+/// Consistent hash load balance implemention, which routes the calls with the same key to the same instance.
+///
+/// The key is derived from the call by the key function, usually with [`hash_key`]. Calls without key are spread at random.
+/// Every instance has a number of points on a hash ring in proportion to its weight, and a call goes to the first point
+/// after its key, so that only the keys next to the points of the added and removed instances move when rebalancing.
+///
+/// # Example:
+/// ```
+/// use logimesh::client::balance::{hash_key, ConsistentHashBalance};
+/// use logimesh::health::{HealthRequest, HealthService, ServeHealth};
+///
+/// let balance = ConsistentHashBalance::<ServeHealth<HealthService>, _>::new(|_, request: &HealthRequest| match request {
+///     HealthRequest::Check { service } => Some(hash_key(service)),
+/// });
+/// ```
+pub struct ConsistentHashBalance<S: Serve, F> {
+    key_fn: F,
+    replicas: u32,
+    ring: RwLock<Arc<Ring<S>>>,
+}
+
+struct Ring<S: Serve> {
+    points: BTreeMap<u64, Address>,
+    channels: HashMap<Address, RpcChannel<S>>,
+}
+
+impl<S: Serve> Clone for Ring<S> {
+    fn clone(&self) -> Self {
+        Self {
+            points: self.points.clone(),
+            channels: self.channels.clone(),
+        }
+    }
+}
+
+impl<S: Serve> Ring<S> {
+    fn empty() -> Self {
+        Self {
+            points: BTreeMap::new(),
+            channels: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, channel: RpcChannel<S>, replicas: u32) {
+        let instance = &channel.config().instance;
+        for point in points(&instance.address, instance.weight.saturating_mul(replicas)) {
+            self.points.insert(point, instance.address.clone());
+        }
+        self.channels.insert(instance.address.clone(), channel);
+    }
+
+    fn remove(&mut self, address: &Address, replicas: u32) {
+        let Some(channel) = self.channels.remove(address) else {
+            return;
+        };
+        for point in points(address, channel.config().instance.weight.saturating_mul(replicas)) {
+            if self.points.get(&point) == Some(address) {
+                self.points.remove(&point);
+            }
+        }
+    }
+}
+
+/// Returns the points of the address on the ring.
+fn points(address: &Address, count: u32) -> impl Iterator<Item = u64> + '_ {
+    (0..count).map(move |i| hash_key((address, i)))
+}
+
+impl<S: Serve, F> ConsistentHashBalance<S, F>
+where
+    F: Fn(&Context, &S::Req) -> Option<u64>,
+{
+    /// Returns a empty [`ConsistentHashBalance`] routing by the key function.
+    pub fn new(key_fn: F) -> Self {
+        Self {
+            key_fn,
+            replicas: DEFAULT_REPLICAS,
+            ring: RwLock::new(Arc::new(Ring::empty())),
+        }
+    }
+
+    /// Set the number of points an instance of weight 1 has on the ring, default is 100.
+    pub fn with_replicas(mut self, replicas: u32) -> Self {
+        self.replicas = replicas.max(1);
+        self
+    }
+}
+
+/// A channel picker which walks the hash ring from the key of the call,
+/// so that the next instances on the ring are the retry candidates.
+pub struct HashRingPicker<S: Serve> {
+    ring: Arc<Ring<S>>,
+    position: u64,
+    steps: usize,
+    picked: Vec<Address>,
+}
+
+impl<S: Serve> Iterator for HashRingPicker<S> {
+    type Item = RpcChannel<S>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.picked.len() < self.ring.channels.len() && self.steps < self.ring.points.len() {
+            let lower = if self.steps == 0 { Bound::Included(self.position) } else { Bound::Excluded(self.position) };
+            let (&point, address) = self.ring.points.range((lower, Bound::Unbounded)).next().or_else(|| self.ring.points.iter().next())?;
+            self.position = point;
+            self.steps += 1;
+            if !self.picked.contains(address) {
+                self.picked.push(address.clone());
+                return self.ring.channels.get(address).cloned();
+            }
+        }
+        None
+    }
+}
+
+impl<S, F> LoadBalance<S> for ConsistentHashBalance<S, F>
+where
+    S: Serve + 'static,
+    S::Req: Send,
+    S::Resp: Send,
+    F: Fn(&Context, &S::Req) -> Option<u64> + Send + Sync + 'static,
+{
+    type ChannelIter = HashRingPicker<S>;
+    fn start_balance(&self, channels: Vec<RpcChannel<S>>) {
+        let mut ring = Ring::empty();
+        for channel in channels {
+            ring.add(channel, self.replicas);
+        }
+        *self.ring.write().unwrap() = Arc::new(ring);
+    }
+    fn get_picker(&self) -> Self::ChannelIter {
+        HashRingPicker {
+            ring: self.ring.read().unwrap().clone(),
+            position: rand::random(),
+            steps: 0,
+            picked: Vec::new(),
+        }
+    }
+    fn get_picker_for(&self, ctx: &Context, request: &S::Req) -> Self::ChannelIter {
+        let Some(key) = (self.key_fn)(ctx, request) else {
+            return self.get_picker();
+        };
+        HashRingPicker {
+            ring: self.ring.read().unwrap().clone(),
+            position: key,
+            steps: 0,
+            picked: Vec::new(),
+        }
+    }
+    fn rebalance(&self, changes: Option<RpcChange<S>>) {
+        let mut current = self.ring.write().unwrap();
+        let Some(changes) = changes else {
+            *current = Arc::new(Ring::empty());
+            return;
+        };
+        let mut ring = Ring::clone(&current);
+        for address in &changes.removed {
+            ring.remove(address, self.replicas);
+        }
+        for channel in changes.updated {
+            let address = &channel.config().instance.address;
+            let weight = ring.channels.get(address).map(|previous| previous.config().instance.weight);
+            if weight == Some(channel.config().instance.weight) {
+                ring.channels.insert(address.clone(), channel);
+            } else {
+                ring.remove(&address.clone(), self.replicas);
+                ring.add(channel, self.replicas);
+            }
+        }
+        for channel in changes.added {
+            ring.add(channel, self.replicas);
+        }
+        *current = Arc::new(ring);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::balance::tests::{addresses, channels};
+    use crate::health::{HealthRequest, HealthService, ServeHealth};
+
+    fn balance() -> ConsistentHashBalance<ServeHealth<HealthService>, impl Fn(&Context, &HealthRequest) -> Option<u64>> {
+        ConsistentHashBalance::new(|_: &Context, request: &HealthRequest| match request {
+            HealthRequest::Check { service } if !service.is_empty() => Some(hash_key(service)),
+            _ => None,
+        })
+    }
+
+    fn pick(balance: &impl LoadBalance<ServeHealth<HealthService>>, key: usize) -> Address {
+        let request = HealthRequest::Check { service: format!("user-{key}") };
+        balance.get_picker_for(&crate::context::current(), &request).next().unwrap().config().instance.address.clone()
+    }
+
+    #[test]
+    fn stable_hash() {
+        // The clients of other releases and platforms must agree on the ring.
+        assert_eq!(hash_key("user-1"), 0xa29924b87e5d9430);
+        assert_eq!(hash_key((&"127.0.0.1:8000".parse::<Address>().unwrap(), 7u32)), 0x67f06aa4667e0477);
+    }
+
+    #[tokio::test]
+    async fn same_key_same_instance() {
+        let (_listeners, channels) = channels(&[1, 1, 1, 1]).await;
+        let balance = balance();
+        balance.start_balance(channels[..3].to_vec());
+        let before: Vec<Address> = (0..1000).map(|key| pick(&balance, key)).collect();
+        assert_eq!(before, (0..1000).map(|key| pick(&balance, key)).collect::<Vec<_>>());
+        for address in addresses(&channels[..3]) {
+            assert!(before.contains(&address));
+        }
+
+        // The picker yields every instance once.
+        let request = HealthRequest::Check { service: String::new() };
+        let mut picked = addresses(&balance.get_picker_for(&crate::context::current(), &request).collect::<Vec<_>>());
+        picked.sort_by_key(|address| address.to_string());
+        let mut expected = addresses(&channels[..3]);
+        expected.sort_by_key(|address| address.to_string());
+        assert_eq!(picked, expected);
+
+        // Only the keys of the removed instance, and the keys taken by the added one, move.
+        let removed = channels[0].config().instance.address.clone();
+        let added = channels[3].config().instance.address.clone();
+        balance.rebalance(Some(RpcChange {
+            all: channels[1..].to_vec(),
+            added: vec![channels[3].clone()],
+            updated: vec![],
+            removed: vec![removed.clone()],
+        }));
+        for (key, before) in before.iter().enumerate() {
+            let after = pick(&balance, key);
+            assert!(after == *before || *before == removed || after == added, "key {key} moved from {before} to {after}");
+        }
+        balance.rebalance(None);
+        assert!(balance.get_picker().next().is_none());
+    }
+}
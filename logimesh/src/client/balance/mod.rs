@@ -6,9 +6,25 @@
 //!
 //! load balance for channel.
 
+pub use consistent_hash::*;
+pub use outlier::*;
+pub use p2c::*;
+pub use peak_ewma::*;
 pub use random::*;
+pub use round_robin::*;
+pub use slow_start::*;
+pub use zone::*;
+mod consistent_hash;
+mod outlier;
+mod p2c;
+mod peak_ewma;
 mod random;
+mod round_robin;
+mod slow_start;
+mod zone;
 use crate::client::channel::RpcChannel;
+use crate::client::core::RpcError;
+use crate::context::Context;
 use crate::net::Address;
 use crate::server::Serve;
 use std::fmt::Debug;
@@ -26,11 +42,27 @@ where
     fn start_balance(&self, channels: Vec<RpcChannel<S>>);
     /// `get_picker` allows to get an RPC channel iterator.
     fn get_picker(&self) -> Self::ChannelIter;
+    /// `get_picker_for` allows to get an RPC channel iterator for the call, so that the policy can route by it.
+    /// The default ignores the call.
+    fn get_picker_for(&self, ctx: &Context, request: &S::Req) -> Self::ChannelIter {
+        let _ = (ctx, request);
+        self.get_picker()
+    }
+    /// `report` is called with the result of every call sent over a picked channel, so that the policy can learn from it.
+    /// The default ignores it.
+    fn report(&self, channel: &RpcChannel<S>, result: &Result<S::Resp, RpcError>) {
+        let _ = (channel, result);
+    }
     /// `rebalance` is the callback method be used in balance stub.
     /// If changes is `Option::None`, it indicates that the channels should be cleared.
     fn rebalance(&self, changes: Option<RpcChange<S>>);
 }
 
+/// Removes the channels of the instances whose weight is zero.
+fn pickable<S: Serve>(channels: Vec<RpcChannel<S>>) -> Vec<RpcChannel<S>> {
+    channels.into_iter().filter(|channel| channel.config().instance.weight > 0).collect()
+}
+
 /// Change indicates the change of the service discover.
 ///
 /// Change contains the difference between the current discovery result and the previous one.
@@ -66,3 +98,34 @@ where
             .finish()
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::client::channel::RpcConfig;
+    use crate::client::discover::Instance;
+    use crate::health::{HealthService, ServeHealth};
+    use std::sync::Arc;
+
+    /// Returns channels to local listeners, one per weight.
+    pub(crate) async fn channels(weights: &[u32]) -> (Vec<std::net::TcpListener>, Vec<RpcChannel<ServeHealth<HealthService>>>) {
+        let mut listeners = Vec::new();
+        let mut channels = Vec::new();
+        for &weight in weights {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let instance = Instance {
+                address: listener.local_addr().unwrap().into(),
+                weight,
+                tags: Default::default(),
+            };
+            channels.push(RpcChannel::new(RpcConfig::new(Arc::new(instance))).await.unwrap());
+            listeners.push(listener);
+        }
+        (listeners, channels)
+    }
+
+    /// Returns the instance addresses of the channels.
+    pub(crate) fn addresses<S: Serve>(channels: &[RpcChannel<S>]) -> Vec<Address> {
+        channels.iter().map(|channel| channel.config().instance.address.clone()).collect()
+    }
+}
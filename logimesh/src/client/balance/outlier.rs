@@ -0,0 +1,306 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Outlier ejection for load balances
+
+use super::{LoadBalance, RpcChange};
+use crate::client::channel::RpcChannel;
+use crate::client::core::RpcError;
+use crate::context::Context;
+use crate::net::Address;
+use crate::server::Serve;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Passive outlier ejection, which takes the channels of the failing instances out of the picker of another load balance for a while.
+///
+/// The results of the calls are reported by [`crate::client::lrcall::LRCall`]. A channel is ejected after a number of
+/// consecutive failures, or when the ratio of its failed calls within an interval is too high. The ejection time doubles
+/// with every ejection in a row, up to a maximum, and is reset once the channel has stayed for the maximum ejection time.
+/// At most a ratio of the channels is ejected at once, so that the instances are not all ejected on a wide outage.
+///
+/// # Example:
+/// ```
+/// use logimesh::client::balance::{OutlierEjectionBalance, RandomBalance};
+/// use logimesh::health::{HealthService, ServeHealth};
+/// use std::time::Duration;
+///
+/// let balance = OutlierEjectionBalance::new(RandomBalance::<ServeHealth<HealthService>>::new())
+///     .with_consecutive_failures(3)
+///     .with_base_ejection_time(Duration::from_secs(10));
+/// ```
+pub struct OutlierEjectionBalance<LB> {
+    inner: LB,
+    consecutive_failures: u32,
+    max_error_rate: f64,
+    min_requests: u32,
+    interval: Duration,
+    base_ejection_time: Duration,
+    max_ejection_time: Duration,
+    max_ejection_ratio: f64,
+    hosts: Mutex<Hosts>,
+    /// The ejected channels with the end of their ejection, which is replaced on the ejections and the rebalances
+    /// only, so that the pickers share it.
+    ejected: RwLock<Arc<HashMap<Address, Instant>>>,
+}
+
+#[derive(Default)]
+struct Hosts {
+    /// The number of channels in the pool.
+    pool: usize,
+    hosts: HashMap<Address, Host>,
+}
+
+#[derive(Default)]
+struct Host {
+    consecutive_failures: u32,
+    /// The calls and failed calls of the current interval.
+    calls: u32,
+    failures: u32,
+    interval_start: Option<Instant>,
+    /// The number of ejections in a row.
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl Host {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| until > now)
+    }
+}
+
+impl Hosts {
+    /// Keeps the state of the channels that stay in the pool.
+    fn reset<S: Serve>(&mut self, channels: &[RpcChannel<S>]) {
+        let mut hosts = HashMap::with_capacity(channels.len());
+        for channel in channels {
+            let address = &channel.config().instance.address;
+            hosts.insert(address.clone(), self.hosts.remove(address).unwrap_or_default());
+        }
+        self.pool = channels.len();
+        self.hosts = hosts;
+    }
+
+    fn ejected(&self, now: Instant) -> HashMap<Address, Instant> {
+        self.hosts.iter().filter_map(|(address, host)| host.ejected_until.filter(|until| *until > now).map(|until| (address.clone(), until))).collect()
+    }
+}
+
+impl<LB> OutlierEjectionBalance<LB> {
+    /// Returns a [`OutlierEjectionBalance`] ejecting the outliers from the picker of the load balance.
+    pub fn new(inner: LB) -> Self {
+        Self {
+            inner,
+            consecutive_failures: 5,
+            max_error_rate: 0.5,
+            min_requests: 10,
+            interval: Duration::from_secs(10),
+            base_ejection_time: Duration::from_secs(30),
+            max_ejection_time: Duration::from_secs(300),
+            max_ejection_ratio: 0.5,
+            hosts: Mutex::new(Hosts::default()),
+            ejected: RwLock::new(Arc::new(HashMap::new())),
+        }
+    }
+
+    /// Set the number of consecutive failures after which a channel is ejected, default is 5, and zero disables it.
+    pub fn with_consecutive_failures(mut self, consecutive_failures: u32) -> Self {
+        self.consecutive_failures = consecutive_failures;
+        self
+    }
+
+    /// Set the ratio of the failed calls within an interval from which a channel is ejected, default is 0.5.
+    pub fn with_max_error_rate(mut self, max_error_rate: f64) -> Self {
+        self.max_error_rate = max_error_rate;
+        self
+    }
+
+    /// Set the number of calls within an interval under which the error rate is ignored, default is 10.
+    pub fn with_min_requests(mut self, min_requests: u32) -> Self {
+        self.min_requests = min_requests.max(1);
+        self
+    }
+
+    /// Set the interval over which the error rate is computed, default is 10s.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set the time of the first ejection, default is 30s.
+    pub fn with_base_ejection_time(mut self, base_ejection_time: Duration) -> Self {
+        self.base_ejection_time = base_ejection_time;
+        self
+    }
+
+    /// Set the maximum ejection time, default is 300s.
+    pub fn with_max_ejection_time(mut self, max_ejection_time: Duration) -> Self {
+        self.max_ejection_time = max_ejection_time;
+        self
+    }
+
+    /// Set the maximum ratio of the channels which are ejected at once, default is 0.5.
+    pub fn with_max_ejection_ratio(mut self, max_ejection_ratio: f64) -> Self {
+        self.max_ejection_ratio = max_ejection_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    fn is_outlier(&self, host: &Host) -> bool {
+        (self.consecutive_failures > 0 && host.consecutive_failures >= self.consecutive_failures)
+            || (host.calls >= self.min_requests && host.failures as f64 / host.calls as f64 >= self.max_error_rate)
+    }
+
+    /// Returns the ejection time of the ejection in a row.
+    fn ejection_time(&self, ejections: u32) -> Duration {
+        self.base_ejection_time.saturating_mul(1u32.checked_shl(ejections - 1).unwrap_or(u32::MAX)).min(self.max_ejection_time)
+    }
+
+    /// Keeps the state of the channels that stay in the pool, and the ejections of those.
+    fn reset<S: Serve>(&self, channels: &[RpcChannel<S>]) {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.reset(channels);
+        *self.ejected.write().unwrap() = Arc::new(hosts.ejected(Instant::now()));
+    }
+
+    fn picker<I>(&self, inner: I) -> OutlierPicker<I> {
+        OutlierPicker {
+            inner,
+            ejected: self.ejected.read().unwrap().clone(),
+            now: Instant::now(),
+        }
+    }
+}
+
+/// A channel picker which skips the ejected channels of another picker.
+pub struct OutlierPicker<I> {
+    inner: I,
+    ejected: Arc<HashMap<Address, Instant>>,
+    now: Instant,
+}
+
+impl<S: Serve, I: Iterator<Item = RpcChannel<S>>> Iterator for OutlierPicker<I> {
+    type Item = RpcChannel<S>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ejected.is_empty() {
+            return self.inner.next();
+        }
+        let (ejected, now) = (&self.ejected, self.now);
+        self.inner.find(|channel| ejected.get(&channel.config().instance.address).map_or(true, |until| *until <= now))
+    }
+}
+
+impl<S, LB> LoadBalance<S> for OutlierEjectionBalance<LB>
+where
+    S: Serve + 'static,
+    S::Req: Send,
+    S::Resp: Send,
+    LB: LoadBalance<S>,
+{
+    type ChannelIter = OutlierPicker<LB::ChannelIter>;
+    fn start_balance(&self, channels: Vec<RpcChannel<S>>) {
+        self.reset(&channels);
+        self.inner.start_balance(channels);
+    }
+    fn get_picker(&self) -> Self::ChannelIter {
+        self.picker(self.inner.get_picker())
+    }
+    fn get_picker_for(&self, ctx: &Context, request: &S::Req) -> Self::ChannelIter {
+        self.picker(self.inner.get_picker_for(ctx, request))
+    }
+    fn report(&self, channel: &RpcChannel<S>, result: &Result<S::Resp, RpcError>) {
+        self.inner.report(channel, result);
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        let ejected = self.ejected.read().unwrap().values().filter(|until| **until > now).count();
+        let max_ejected = (hosts.pool as f64 * self.max_ejection_ratio) as usize;
+        let address = &channel.config().instance.address;
+        let Some(host) = hosts.hosts.get_mut(address) else {
+            return;
+        };
+        // The calls sent before the ejection still complete.
+        if host.is_ejected(now) {
+            return;
+        }
+        if host.interval_start.map_or(true, |start| now.saturating_duration_since(start) >= self.interval) {
+            (host.calls, host.failures, host.interval_start) = (0, 0, Some(now));
+        }
+        host.calls += 1;
+        if result.is_err() {
+            host.failures += 1;
+            host.consecutive_failures += 1;
+        } else {
+            host.consecutive_failures = 0;
+        }
+        if !self.is_outlier(host) || ejected >= max_ejected {
+            return;
+        }
+        if host.ejected_until.is_some_and(|until| now.saturating_duration_since(until) >= self.max_ejection_time) {
+            host.ejections = 0;
+        }
+        host.ejections += 1;
+        let ejection_time = self.ejection_time(host.ejections);
+        host.ejected_until = Some(now + ejection_time);
+        (host.consecutive_failures, host.calls, host.failures, host.interval_start) = (0, 0, 0, None);
+        warn!("[LOGIMESH] eject the outlier {address} for {ejection_time:?}");
+        *self.ejected.write().unwrap() = Arc::new(hosts.ejected(now));
+    }
+    fn rebalance(&self, changes: Option<RpcChange<S>>) {
+        self.reset(changes.as_ref().map_or(&[][..], |changes| &changes.all));
+        self.inner.rebalance(changes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::balance::tests::{addresses, channels};
+    use crate::client::balance::RoundRobinBalance;
+    use crate::health::{HealthResponse, ServingStatus};
+
+    #[tokio::test]
+    async fn eject_failing_channel() {
+        let (_listeners, channels) = channels(&[1, 1, 1]).await;
+        let balance = OutlierEjectionBalance::new(RoundRobinBalance::new())
+            .with_consecutive_failures(3)
+            .with_base_ejection_time(Duration::from_millis(50));
+        balance.start_balance(channels.clone());
+        let failed = Err(RpcError::Shutdown);
+        for _ in 0..2 {
+            balance.report(&channels[0], &failed);
+        }
+        // A success breaks the failures in a row.
+        balance.report(&channels[0], &Ok(HealthResponse::Check(ServingStatus::Serving)));
+        balance.report(&channels[0], &failed);
+        assert_eq!(balance.get_picker().count(), 3);
+        for _ in 0..2 {
+            balance.report(&channels[0], &failed);
+        }
+        for _ in 0..10 {
+            let picked = addresses(&balance.get_picker().collect::<Vec<_>>());
+            assert_eq!(picked.len(), 2);
+            assert!(!picked.contains(&channels[0].config().instance.address));
+        }
+
+        // No more than half of the channels are ejected.
+        for _ in 0..3 {
+            balance.report(&channels[1], &failed);
+        }
+        assert_eq!(balance.get_picker().count(), 2);
+
+        // The channel is back after the ejection time, and the next ejection lasts twice as long.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(balance.get_picker().count(), 3);
+        for _ in 0..3 {
+            balance.report(&channels[0], &failed);
+        }
+        let hosts = balance.hosts.lock().unwrap();
+        let host = &hosts.hosts[&channels[0].config().instance.address];
+        assert_eq!(host.ejections, 2);
+        assert!(host.ejected_until.unwrap() > Instant::now() + Duration::from_millis(60));
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Power of two choices load balance implemention
+
+use super::{pickable, LoadBalance, RpcChange};
+use crate::client::channel::RpcChannel;
+use crate::server::Serve;
+use rand::Rng;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Power of two choices load balance implemention, which samples two channels at random
+/// and picks the one with the fewer outstanding requests for its weight.
+///
+/// Unlike a static weight, the outstanding requests of [`crate::client::channel::ChannelStats`] grow as soon as
+/// an instance slows down, so the traffic moves away from it. Channels of instances whose weight is zero are never picked.
+pub struct P2cBalance<S: Serve> {
+    channels: RwLock<Arc<Vec<RpcChannel<S>>>>,
+}
+
+impl<S: Serve> P2cBalance<S> {
+    /// Returns a empty [`P2cBalance`]
+    pub fn new() -> Self {
+        Self {
+            channels: RwLock::new(Arc::new(Vec::new())),
+        }
+    }
+}
+
+impl<S: Serve> Default for P2cBalance<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A channel picker which yields the power of two choices first,
+/// and then the other channels as retry candidates, the least loaded first.
+pub struct P2cPicker<S: Serve> {
+    channels: Arc<Vec<RpcChannel<S>>>,
+    cost: Cost,
+    first: Option<usize>,
+    rest: Option<std::vec::IntoIter<usize>>,
+}
+
+impl<S: Serve> P2cPicker<S> {
+    pub(super) fn new(channels: Arc<Vec<RpcChannel<S>>>, cost: Cost) -> Self {
+        Self {
+            first: two_choices(&channels, cost),
+            channels,
+            cost,
+            rest: None,
+        }
+    }
+}
+
+impl<S: Serve> Iterator for P2cPicker<S> {
+    type Item = RpcChannel<S>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = match &mut self.rest {
+            Some(rest) => rest,
+            None => {
+                let first = self.first?;
+                let mut rest: Vec<usize> = (0..self.channels.len()).filter(|&i| i != first).collect();
+                rest.sort_by(|&a, &b| self.cost.of(&self.channels[a]).total_cmp(&self.cost.of(&self.channels[b])));
+                self.rest = Some(rest.into_iter());
+                return Some(self.channels[first].clone());
+            },
+        };
+        rest.next().map(|i| self.channels[i].clone())
+    }
+}
+
+/// The load of a channel compared by [`P2cPicker`].
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Cost {
+    /// The outstanding requests.
+    Outstanding,
+    /// The outstanding requests times the peak latency, raised by the error rate.
+    PeakEwma {
+        /// The latency of the channels without completed calls.
+        default_latency: Duration,
+        /// How much a channel whose calls all fail costs more.
+        error_penalty: f64,
+    },
+}
+
+impl Cost {
+    /// Returns the cost of the channel for its weight, counting the request to be sent.
+    fn of<S: Serve>(&self, channel: &RpcChannel<S>) -> f64 {
+        let stats = channel.stats();
+        let outstanding = (stats.in_flight() + 1) as f64;
+        let cost = match *self {
+            Cost::Outstanding => outstanding,
+            Cost::PeakEwma { default_latency, error_penalty } => {
+                let latency = stats.latency().unwrap_or(default_latency).as_secs_f64();
+                // Keeps a floor, so that the outstanding requests still count when the latency is negligible.
+                (latency + 1e-6) * outstanding * (1.0 + error_penalty * stats.error_rate())
+            },
+        };
+        cost / channel.config().instance.weight as f64
+    }
+}
+
+/// Samples two distinct channels and returns the cheaper one.
+fn two_choices<S: Serve>(channels: &[RpcChannel<S>], cost: Cost) -> Option<usize> {
+    match channels.len() {
+        0 => None,
+        1 => Some(0),
+        len => {
+            let mut rng = rand::thread_rng();
+            let a = rng.gen_range(0..len);
+            let mut b = rng.gen_range(0..len - 1);
+            if b >= a {
+                b += 1;
+            }
+            Some(if cost.of(&channels[b]) < cost.of(&channels[a]) { b } else { a })
+        },
+    }
+}
+
+impl<S> LoadBalance<S> for P2cBalance<S>
+where
+    S: Serve + 'static,
+    S::Req: Send,
+    S::Resp: Send,
+{
+    type ChannelIter = P2cPicker<S>;
+    fn start_balance(&self, channels: Vec<RpcChannel<S>>) {
+        *self.channels.write().unwrap() = Arc::new(pickable(channels));
+    }
+    fn get_picker(&self) -> Self::ChannelIter {
+        P2cPicker::new(self.channels.read().unwrap().clone(), Cost::Outstanding)
+    }
+    fn rebalance(&self, changes: Option<RpcChange<S>>) {
+        *self.channels.write().unwrap() = Arc::new(changes.map(|changes| pickable(changes.all)).unwrap_or_default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::balance::tests::channels;
+    use crate::client::Stub;
+    use crate::health::HealthRequest;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn avoid_loaded_channel() {
+        // The listeners never answer, so the calls stay in flight.
+        let (_listeners, channels) = channels(&[1, 1]).await;
+        let slow = channels[0].clone();
+        let calls: Vec<_> = (0..3)
+            .map(|_| {
+                let slow = slow.clone();
+                tokio::spawn(async move { slow.call(crate::context::current(), HealthRequest::Check { service: String::new() }).await })
+            })
+            .collect();
+        while slow.stats().in_flight() < 3 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let balance = P2cBalance::new();
+        balance.start_balance(channels.clone());
+        for _ in 0..10 {
+            let picked: Vec<_> = balance.get_picker().map(|channel| channel.config().instance.address.clone()).collect();
+            assert_eq!(picked, vec![channels[1].config().instance.address.clone(), slow.config().instance.address.clone()]);
+        }
+
+        // Cancelled calls are no longer in flight.
+        for call in calls {
+            call.abort();
+            let _ = call.await;
+        }
+        assert_eq!(slow.stats().in_flight(), 0);
+    }
+}
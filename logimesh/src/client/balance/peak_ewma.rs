@@ -0,0 +1,115 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Peak EWMA load balance implemention
+
+use super::p2c::Cost;
+use super::{pickable, LoadBalance, P2cPicker, RpcChange};
+use crate::client::channel::RpcChannel;
+use crate::server::Serve;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Default latency of the channels without completed calls.
+const DEFAULT_LATENCY: Duration = Duration::from_millis(30);
+/// Default cost multiplier of a channel whose calls all fail.
+const DEFAULT_ERROR_PENALTY: f64 = 10.0;
+
+/// Peak EWMA load balance implemention, which prefers the fastest instances.
+///
+/// The cost of a channel is its peak moving average latency, see [`crate::client::channel::ChannelStats::latency`],
+/// times its outstanding requests, and is raised by its moving average error rate. Two channels are sampled
+/// at random and the cheaper one is picked. As the measurements decay without calls, an instance that was slow
+/// gets traffic again after a while. Channels of instances whose weight is zero are never picked.
+pub struct PeakEwmaBalance<S: Serve> {
+    channels: RwLock<Arc<Vec<RpcChannel<S>>>>,
+    default_latency: Duration,
+    error_penalty: f64,
+}
+
+impl<S: Serve> PeakEwmaBalance<S> {
+    /// Returns a empty [`PeakEwmaBalance`]
+    pub fn new() -> Self {
+        Self {
+            channels: RwLock::new(Arc::new(Vec::new())),
+            default_latency: DEFAULT_LATENCY,
+            error_penalty: DEFAULT_ERROR_PENALTY,
+        }
+    }
+
+    /// Set the latency of the channels without completed calls, default is 30ms.
+    pub fn with_default_latency(mut self, default_latency: Duration) -> Self {
+        self.default_latency = default_latency;
+        self
+    }
+
+    /// Set how many times more a channel whose calls all fail costs, on top of its latency, default is 10.
+    pub fn with_error_penalty(mut self, error_penalty: f64) -> Self {
+        self.error_penalty = error_penalty.max(0.0);
+        self
+    }
+}
+
+impl<S: Serve> Default for PeakEwmaBalance<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> LoadBalance<S> for PeakEwmaBalance<S>
+where
+    S: Serve + 'static,
+    S::Req: Send,
+    S::Resp: Send,
+{
+    type ChannelIter = P2cPicker<S>;
+    fn start_balance(&self, channels: Vec<RpcChannel<S>>) {
+        *self.channels.write().unwrap() = Arc::new(pickable(channels));
+    }
+    fn get_picker(&self) -> Self::ChannelIter {
+        let cost = Cost::PeakEwma {
+            default_latency: self.default_latency,
+            error_penalty: self.error_penalty,
+        };
+        P2cPicker::new(self.channels.read().unwrap().clone(), cost)
+    }
+    fn rebalance(&self, changes: Option<RpcChange<S>>) {
+        *self.channels.write().unwrap() = Arc::new(changes.map(|changes| pickable(changes.all)).unwrap_or_default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::balance::tests::{addresses, channels};
+
+    #[tokio::test]
+    async fn prefer_fast_channel() {
+        let (_listeners, channels) = channels(&[1, 1, 1]).await;
+        let (slow, fast, failing) = (&channels[0], &channels[1], &channels[2]);
+        assert_eq!(slow.stats().latency(), None);
+
+        // The peak latency is taken at once, and lower ones only pull it down over time.
+        slow.stats().observe(Duration::from_millis(10), false);
+        slow.stats().observe(Duration::from_millis(200), false);
+        slow.stats().observe(Duration::from_millis(1), false);
+        assert!(slow.stats().latency().unwrap() > Duration::from_millis(150));
+        fast.stats().observe(Duration::from_millis(1), false);
+        failing.stats().observe(Duration::from_millis(1), true);
+        assert!(failing.stats().error_rate() > 0.9);
+
+        let balance = PeakEwmaBalance::new();
+        balance.start_balance(channels.clone());
+        // The slow channel loses any sample, and is the last retry candidate.
+        let mut first_picks = Vec::new();
+        for _ in 0..20 {
+            let picked = addresses(&balance.get_picker().collect::<Vec<_>>());
+            assert_eq!(picked.len(), 3);
+            assert_eq!(picked[2], slow.config().instance.address);
+            first_picks.push(picked[0].clone());
+        }
+        assert!(first_picks.contains(&fast.config().instance.address));
+    }
+}
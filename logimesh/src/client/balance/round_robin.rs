@@ -0,0 +1,224 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Round-robin load balance implemention
+
+use super::{pickable, LoadBalance, RpcChange};
+use crate::client::channel::RpcChannel;
+use crate::net::Address;
+use crate::server::Serve;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A channel picker which yields the channels in turn from a start position,
+/// so that the channels after the first one are the retry candidates.
+pub struct RoundRobinPicker<S: Serve> {
+    channels: Arc<Vec<RpcChannel<S>>>,
+    start: usize,
+    offset: usize,
+}
+
+impl<S: Serve> RoundRobinPicker<S> {
+    fn new(channels: Arc<Vec<RpcChannel<S>>>, start: usize) -> Self {
+        let start = if channels.is_empty() { 0 } else { start % channels.len() };
+        Self { channels, start, offset: 0 }
+    }
+}
+
+impl<S: Serve> Iterator for RoundRobinPicker<S> {
+    type Item = RpcChannel<S>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.channels.len() {
+            return None;
+        }
+        let channel = self.channels[(self.start + self.offset) % self.channels.len()].clone();
+        self.offset += 1;
+        Some(channel)
+    }
+}
+
+/// Round-robin load balance implemention, which picks the channels in turn.
+///
+/// Channels of instances whose weight is zero are never picked.
+pub struct RoundRobinBalance<S: Serve> {
+    channels: RwLock<Arc<Vec<RpcChannel<S>>>>,
+    cursor: AtomicUsize,
+}
+
+impl<S: Serve> RoundRobinBalance<S> {
+    /// Returns a empty [`RoundRobinBalance`]
+    pub fn new() -> Self {
+        Self {
+            channels: RwLock::new(Arc::new(Vec::new())),
+            cursor: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<S: Serve> Default for RoundRobinBalance<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> LoadBalance<S> for RoundRobinBalance<S>
+where
+    S: Serve + 'static,
+    S::Req: Send,
+    S::Resp: Send,
+{
+    type ChannelIter = RoundRobinPicker<S>;
+    fn start_balance(&self, channels: Vec<RpcChannel<S>>) {
+        *self.channels.write().unwrap() = Arc::new(pickable(channels));
+    }
+    fn get_picker(&self) -> Self::ChannelIter {
+        let channels = self.channels.read().unwrap().clone();
+        RoundRobinPicker::new(channels, self.cursor.fetch_add(1, Ordering::Relaxed))
+    }
+    fn rebalance(&self, changes: Option<RpcChange<S>>) {
+        let next = Arc::new(changes.map(|changes| pickable(changes.all)).unwrap_or_default());
+        let mut channels = self.channels.write().unwrap();
+        // The turn goes on with the first channel that stays, from the one that would have been picked next.
+        if !channels.is_empty() {
+            let cursor = self.cursor.load(Ordering::Relaxed);
+            let position = (0..channels.len())
+                .map(|offset| &channels[(cursor + offset) % channels.len()].config().instance.address)
+                .find_map(|address| next.iter().position(|channel| &channel.config().instance.address == address));
+            self.cursor.store(position.unwrap_or_default(), Ordering::Relaxed);
+        }
+        *channels = next;
+    }
+}
+
+/// Smooth weighted round-robin load balance implemention, as done by nginx.
+///
+/// Each instance is picked in proportion to its weight, and the picks of a heavy instance are spread
+/// among the others instead of coming in a row, e.g. the weights `5, 1, 1` give `a a b a c a a`.
+/// The state of the instances that stay is kept across [`LoadBalance::rebalance`].
+pub struct WeightedRoundRobinBalance<S: Serve> {
+    state: Mutex<SmoothWeights<S>>,
+}
+
+struct SmoothWeights<S: Serve> {
+    channels: Arc<Vec<RpcChannel<S>>>,
+    current: Vec<i64>,
+}
+
+impl<S: Serve> SmoothWeights<S> {
+    fn reset(&mut self, channels: Vec<RpcChannel<S>>) {
+        let previous: HashMap<&Address, i64> = self.channels.iter().map(|channel| &channel.config().instance.address).zip(self.current.iter().copied()).collect();
+        let channels = pickable(channels);
+        self.current = channels.iter().map(|channel| previous.get(&channel.config().instance.address).copied().unwrap_or_default()).collect();
+        self.channels = Arc::new(channels);
+    }
+}
+
+impl<S: Serve> WeightedRoundRobinBalance<S> {
+    /// Returns a empty [`WeightedRoundRobinBalance`]
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(SmoothWeights {
+                channels: Arc::new(Vec::new()),
+                current: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl<S: Serve> Default for WeightedRoundRobinBalance<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> LoadBalance<S> for WeightedRoundRobinBalance<S>
+where
+    S: Serve + 'static,
+    S::Req: Send,
+    S::Resp: Send,
+{
+    type ChannelIter = RoundRobinPicker<S>;
+    fn start_balance(&self, channels: Vec<RpcChannel<S>>) {
+        self.state.lock().unwrap().reset(channels);
+    }
+    fn get_picker(&self) -> Self::ChannelIter {
+        let mut state = self.state.lock().unwrap();
+        let SmoothWeights { channels, current } = &mut *state;
+        let start = smooth_pick(current, channels.iter().map(|channel| channel.config().instance.weight)).unwrap_or_default();
+        RoundRobinPicker::new(channels.clone(), start)
+    }
+    fn rebalance(&self, changes: Option<RpcChange<S>>) {
+        self.state.lock().unwrap().reset(changes.map(|changes| changes.all).unwrap_or_default());
+    }
+}
+
+/// Raises the current weight of every instance by its weight, and picks the highest one,
+/// whose current weight is then lowered by the sum of the weights.
+fn smooth_pick(current: &mut [i64], weights: impl Iterator<Item = u32>) -> Option<usize> {
+    let mut total = 0;
+    let mut best: Option<usize> = None;
+    for (i, weight) in weights.enumerate() {
+        current[i] += weight as i64;
+        total += weight as i64;
+        if best.map_or(true, |best| current[i] > current[best]) {
+            best = Some(i);
+        }
+    }
+    let best = best?;
+    current[best] -= total;
+    Some(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::balance::tests::{addresses, channels};
+
+    #[test]
+    fn smooth_weighted_sequence() {
+        let mut current = vec![0; 3];
+        let picks: Vec<usize> = (0..7).map(|_| smooth_pick(&mut current, [5, 1, 1].into_iter()).unwrap()).collect();
+        assert_eq!(picks, vec![0, 0, 1, 0, 2, 0, 0]);
+        assert_eq!(current, vec![0, 0, 0]);
+        assert_eq!(smooth_pick(&mut [], [].into_iter()), None);
+    }
+
+    #[tokio::test]
+    async fn round_robin_keeps_turn_across_rebalance() {
+        let (_listeners, channels) = channels(&[1, 1, 1, 0]).await;
+        let balance = RoundRobinBalance::new();
+        balance.start_balance(channels.clone());
+        let expected = addresses(&channels[..3]);
+        assert_eq!(addresses(&balance.get_picker().collect::<Vec<_>>()), expected);
+        assert_eq!(addresses(&balance.get_picker().take(1).collect::<Vec<_>>()), vec![expected[1].clone()]);
+
+        balance.rebalance(Some(RpcChange {
+            all: channels[1..].to_vec(),
+            added: vec![],
+            updated: vec![],
+            removed: vec![expected[0].clone()],
+        }));
+        // The turn goes on with the third channel.
+        assert_eq!(addresses(&balance.get_picker().collect::<Vec<_>>()), vec![expected[2].clone(), expected[1].clone()]);
+        balance.rebalance(None);
+        assert!(balance.get_picker().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn weighted_round_robin_spreads_picks() {
+        let (_listeners, channels) = channels(&[5, 1, 1]).await;
+        let balance = WeightedRoundRobinBalance::new();
+        balance.start_balance(channels.clone());
+        let addresses_of_channels = addresses(&channels);
+        let picks: Vec<Address> = (0..7).map(|_| balance.get_picker().next().unwrap().config().instance.address.clone()).collect();
+        let expected: Vec<Address> = [0, 0, 1, 0, 2, 0, 0].into_iter().map(|i| addresses_of_channels[i].clone()).collect();
+        assert_eq!(picks, expected);
+
+        // The picker yields the other channels as retry candidates.
+        assert_eq!(balance.get_picker().count(), 3);
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Slow start for load balances
+
+use super::{LoadBalance, RpcChange};
+use crate::client::channel::RpcChannel;
+use crate::client::core::RpcError;
+use crate::client::discover::Instance;
+use crate::context::Context;
+use crate::net::Address;
+use crate::server::Serve;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Default factor of the weights given to the inner load balance, so that the weight of an instance of weight 1 can ramp up.
+const DEFAULT_WEIGHT_SCALE: u32 = 100;
+/// Number of weight updates over the slow start window.
+const STEPS: u32 = 20;
+
+/// Slow start, which ramps up the weight of the newly added instances for another load balance.
+///
+/// The instances in [`RpcChange::added`] start with a ratio of their weight, which grows linearly
+/// up to their full weight over the slow start window, so that a freshly deployed instance warms up before taking
+/// its full share of the calls. The instances the client starts with have their full weight at once.
+///
+/// The weights given to the inner load balance are scaled by [`SlowStartBalance::with_weight_scale`], 100 by default.
+/// While an instance ramps up, the inner load balance is rebalanced 20 times over the window. The ramp is only locked
+/// by the pickers while an instance ramps up.
+///
+/// A [`super::ConsistentHashBalance`] puts a number of points on its ring in proportion to the weight, and rebuilds it
+/// on every rebalance, so it is best given a smaller scale, or fewer replicas per weight, e.g.
+/// `ConsistentHashBalance::new(key_fn).with_replicas(1)` with the default scale.
+///
+/// # Example:
+/// ```
+/// use logimesh::client::balance::{SlowStartBalance, WeightedRoundRobinBalance};
+/// use logimesh::health::{HealthService, ServeHealth};
+/// use std::time::Duration;
+///
+/// let balance: SlowStartBalance<ServeHealth<HealthService>, _> = SlowStartBalance::new(WeightedRoundRobinBalance::<ServeHealth<HealthService>>::new()).with_window(Duration::from_secs(60));
+/// ```
+pub struct SlowStartBalance<S: Serve, LB> {
+    inner: LB,
+    window: Duration,
+    min_weight_ratio: f64,
+    weight_scale: u32,
+    /// Whether an instance ramps up, which spares the pickers the lock of the ramp otherwise.
+    ramping: AtomicBool,
+    ramp: Mutex<Ramp<S>>,
+}
+
+struct Ramp<S: Serve> {
+    entries: Vec<Entry<S>>,
+    last_step: Option<Instant>,
+}
+
+struct Entry<S: Serve> {
+    /// The channel as discovered.
+    channel: RpcChannel<S>,
+    /// The channel with the weight given to the inner load balance.
+    scaled: RpcChannel<S>,
+    /// When the instance was added, while its weight ramps up.
+    added: Option<Instant>,
+}
+
+/// Returns a clone of the channel with the weight.
+fn with_weight<S: Serve>(channel: &RpcChannel<S>, weight: u32) -> RpcChannel<S> {
+    channel.clone_update_instance(Arc::new(Instance {
+        weight,
+        ..Instance::clone(&channel.config().instance)
+    }))
+}
+
+impl<S: Serve, LB> SlowStartBalance<S, LB> {
+    /// Returns a [`SlowStartBalance`] ramping up the weights for the load balance.
+    pub fn new(inner: LB) -> Self {
+        Self {
+            inner,
+            window: Duration::from_secs(30),
+            min_weight_ratio: 0.1,
+            weight_scale: DEFAULT_WEIGHT_SCALE,
+            ramping: AtomicBool::new(false),
+            ramp: Mutex::new(Ramp { entries: Vec::new(), last_step: None }),
+        }
+    }
+
+    /// Set the time over which the weight of a new instance ramps up, default is 30s, and zero disables slow start.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Set the ratio of its weight a new instance starts with, default is 0.1.
+    pub fn with_min_weight_ratio(mut self, min_weight_ratio: f64) -> Self {
+        self.min_weight_ratio = min_weight_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set the factor of the weights given to the inner load balance, default is 100, which sets the precision
+    /// of the ramp up for the instances of weight 1.
+    pub fn with_weight_scale(mut self, weight_scale: u32) -> Self {
+        self.weight_scale = weight_scale.max(1);
+        self
+    }
+
+    /// Returns when the instance was added if its weight still ramps up.
+    fn ramping(&self, added: Option<Instant>, now: Instant) -> Option<Instant> {
+        added.filter(|added| now.saturating_duration_since(*added) < self.window)
+    }
+
+    /// Returns the scaled weight of the instance.
+    fn weight(&self, weight: u32, added: Option<Instant>, now: Instant) -> u32 {
+        let full = weight.saturating_mul(self.weight_scale);
+        let Some(added) = self.ramping(added, now) else {
+            return full;
+        };
+        let progress = now.saturating_duration_since(added).as_secs_f64() / self.window.as_secs_f64();
+        let ratio = self.min_weight_ratio + (1.0 - self.min_weight_ratio) * progress;
+        ((full as f64 * ratio).ceil() as u32).min(full)
+    }
+}
+
+impl<S, LB> SlowStartBalance<S, LB>
+where
+    S: Serve + 'static,
+    S::Req: Send,
+    S::Resp: Send,
+    LB: LoadBalance<S>,
+{
+    /// Gives the ramped up weights to the inner load balance.
+    fn step(&self) {
+        if !self.ramping.load(Ordering::Acquire) {
+            return;
+        }
+        let now = Instant::now();
+        let mut ramp = self.ramp.lock().unwrap();
+        if ramp.entries.iter().all(|entry| entry.added.is_none()) {
+            self.ramping.store(false, Ordering::Release);
+            return;
+        }
+        if ramp.last_step.is_some_and(|last| now.saturating_duration_since(last) < self.window / STEPS) {
+            return;
+        }
+        ramp.last_step = Some(now);
+        let mut updated = Vec::new();
+        for entry in ramp.entries.iter_mut().filter(|entry| entry.added.is_some()) {
+            let weight = self.weight(entry.channel.config().instance.weight, entry.added, now);
+            if weight != entry.scaled.config().instance.weight {
+                entry.scaled = with_weight(&entry.channel, weight);
+                updated.push(entry.scaled.clone());
+            }
+            entry.added = self.ramping(entry.added, now);
+        }
+        if updated.is_empty() {
+            return;
+        }
+        self.inner.rebalance(Some(RpcChange {
+            all: ramp.entries.iter().map(|entry| entry.scaled.clone()).collect(),
+            added: Vec::new(),
+            updated,
+            removed: Vec::new(),
+        }));
+    }
+}
+
+impl<S, LB> LoadBalance<S> for SlowStartBalance<S, LB>
+where
+    S: Serve + 'static,
+    S::Req: Send,
+    S::Resp: Send,
+    LB: LoadBalance<S>,
+{
+    type ChannelIter = LB::ChannelIter;
+    fn start_balance(&self, channels: Vec<RpcChannel<S>>) {
+        let mut ramp = self.ramp.lock().unwrap();
+        ramp.entries = channels
+            .into_iter()
+            .map(|channel| Entry {
+                scaled: with_weight(&channel, channel.config().instance.weight.saturating_mul(self.weight_scale)),
+                channel,
+                added: None,
+            })
+            .collect();
+        self.ramping.store(false, Ordering::Release);
+        self.inner.start_balance(ramp.entries.iter().map(|entry| entry.scaled.clone()).collect());
+    }
+    fn get_picker(&self) -> Self::ChannelIter {
+        self.step();
+        self.inner.get_picker()
+    }
+    fn get_picker_for(&self, ctx: &Context, request: &S::Req) -> Self::ChannelIter {
+        self.step();
+        self.inner.get_picker_for(ctx, request)
+    }
+    fn report(&self, channel: &RpcChannel<S>, result: &Result<S::Resp, RpcError>) {
+        self.inner.report(channel, result)
+    }
+    fn rebalance(&self, changes: Option<RpcChange<S>>) {
+        let mut ramp = self.ramp.lock().unwrap();
+        let Some(changes) = changes else {
+            ramp.entries.clear();
+            self.ramping.store(false, Ordering::Release);
+            self.inner.rebalance(None);
+            return;
+        };
+        let now = Instant::now();
+        let mut previous: HashMap<Address, Entry<S>> = ramp.entries.drain(..).map(|entry| (entry.channel.config().instance.address.clone(), entry)).collect();
+        let added_addresses: HashSet<&Address> = changes.added.iter().map(|channel| &channel.config().instance.address).collect();
+        let (mut added, mut updated) = (Vec::new(), Vec::new());
+        for channel in changes.all {
+            let is_added = added_addresses.contains(&channel.config().instance.address);
+            let previous = previous.remove(&channel.config().instance.address);
+            let since = if is_added { Some(now) } else { previous.as_ref().and_then(|previous| previous.added) };
+            let weight = self.weight(channel.config().instance.weight, since, now);
+            let scaled = match previous {
+                Some(previous) if !is_added && previous.channel.config().instance == channel.config().instance && previous.scaled.config().instance.weight == weight => previous.scaled,
+                Some(_) if !is_added => {
+                    let scaled = with_weight(&channel, weight);
+                    updated.push(scaled.clone());
+                    scaled
+                },
+                _ => {
+                    let scaled = with_weight(&channel, weight);
+                    added.push(scaled.clone());
+                    scaled
+                },
+            };
+            ramp.entries.push(Entry {
+                channel,
+                scaled,
+                added: self.ramping(since, now),
+            });
+        }
+        self.ramping.store(ramp.entries.iter().any(|entry| entry.added.is_some()), Ordering::Release);
+        self.inner.rebalance(Some(RpcChange {
+            all: ramp.entries.iter().map(|entry| entry.scaled.clone()).collect(),
+            added,
+            updated,
+            removed: changes.removed,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::balance::tests::channels;
+    use crate::client::balance::WeightedRoundRobinBalance;
+    use crate::health::{HealthService, ServeHealth};
+
+    fn weights(balance: &impl LoadBalance<ServeHealth<HealthService>>) -> HashMap<Address, u32> {
+        balance.get_picker().map(|channel| (channel.config().instance.address.clone(), channel.config().instance.weight)).collect()
+    }
+
+    #[tokio::test]
+    async fn ramp_up_added_channel() {
+        let (_listeners, channels) = channels(&[1, 1]).await;
+        let added = RpcChange {
+            all: channels.clone(),
+            added: vec![channels[1].clone()],
+            updated: vec![],
+            removed: vec![],
+        };
+
+        // The added instance takes a tenth of the share of the other one at first.
+        let balance = SlowStartBalance::new(WeightedRoundRobinBalance::new()).with_window(Duration::from_secs(60));
+        balance.start_balance(channels[..1].to_vec());
+        balance.rebalance(Some(added));
+        let picks = (0..1110).filter(|_| balance.get_picker().next().unwrap().config().instance.address == channels[1].config().instance.address).count();
+        assert!((100..=120).contains(&picks), "{picks}");
+
+        let balance = SlowStartBalance::new(WeightedRoundRobinBalance::new()).with_window(Duration::from_millis(100));
+        balance.start_balance(channels[..1].to_vec());
+        balance.rebalance(Some(RpcChange {
+            all: channels.clone(),
+            added: vec![channels[1].clone()],
+            updated: vec![],
+            removed: vec![],
+        }));
+        let started = weights(&balance);
+        assert_eq!(started[&channels[0].config().instance.address], 100);
+        assert!((10..20).contains(&started[&channels[1].config().instance.address]));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let halfway = weights(&balance)[&channels[1].config().instance.address];
+        assert!((50..=100).contains(&halfway), "{halfway}");
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(weights(&balance)[&channels[1].config().instance.address], 100);
+        assert!(balance.ramp.lock().unwrap().entries.iter().all(|entry| entry.added.is_none()));
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Zone-aware load balance implemention
+
+use super::{LoadBalance, RpcChange};
+use crate::client::channel::RpcChannel;
+use crate::client::core::RpcError;
+use crate::component::Endpoint;
+use crate::context::Context;
+use crate::net::Address;
+use crate::server::Serve;
+use faststr::FastStr;
+use std::collections::HashSet;
+use std::iter::Chain;
+use std::sync::{Arc, RwLock};
+
+/// Default [`crate::client::discover::Instance::tags`] key of the zone of an instance.
+pub const ZONE_TAG: &str = "zone";
+
+/// [`Endpoint`] tag of the zone the client runs in, see [`ZoneAwareBalance`].
+///
+/// # Example:
+/// ```
+/// use logimesh::client::balance::LocalZone;
+/// use logimesh::component::Endpoint;
+///
+/// let mut endpoint = Endpoint::new("hello");
+/// endpoint.insert::<LocalZone>("us-east-1a".into());
+/// ```
+pub struct LocalZone;
+
+/// Zone-aware load balance implemention, which prefers the channels of the instances in the [`LocalZone`] of the client.
+///
+/// The zone of an instance is its [`ZONE_TAG`] tag. The first balance picks among the channels of the local zone,
+/// and the second one among the channels of the other zones, which are also the retry candidates after the local ones.
+///
+/// A local channel is healthy while its error rate, see [`crate::client::channel::ChannelStats::error_rate`], stays below
+/// the maximum error rate. When the weight of the healthy local channels falls below the minimum healthy ratio of
+/// the local weight, the calls spill over to the other zones in proportion, and they all do when no local channel is left.
+/// Without a [`LocalZone`] on the endpoint, all channels are local.
+///
+/// # Example:
+/// ```
+/// use logimesh::client::balance::{LocalZone, RoundRobinBalance, ZoneAwareBalance};
+/// use logimesh::component::Endpoint;
+/// use logimesh::health::{HealthService, ServeHealth};
+///
+/// let mut endpoint = Endpoint::new("health");
+/// endpoint.insert::<LocalZone>("us-east-1a".into());
+/// type Balance = RoundRobinBalance<ServeHealth<HealthService>>;
+/// let balance: ZoneAwareBalance<ServeHealth<HealthService>, Balance> = ZoneAwareBalance::new(&endpoint, Balance::new(), Balance::new()).with_min_healthy_ratio(0.7);
+/// ```
+pub struct ZoneAwareBalance<S: Serve, LB> {
+    zone: Option<FastStr>,
+    zone_tag: FastStr,
+    min_healthy_ratio: f64,
+    max_error_rate: f64,
+    local: LB,
+    remote: LB,
+    local_channels: RwLock<Arc<Vec<RpcChannel<S>>>>,
+}
+
+impl<S: Serve, LB> ZoneAwareBalance<S, LB> {
+    /// Returns a empty [`ZoneAwareBalance`] for the client of the endpoint, which picks the local channels with `local`
+    /// and the others with `remote`.
+    pub fn new(endpoint: &Endpoint, local: LB, remote: LB) -> Self {
+        Self {
+            zone: endpoint.get::<LocalZone>().cloned(),
+            zone_tag: FastStr::from_static_str(ZONE_TAG),
+            min_healthy_ratio: 0.5,
+            max_error_rate: 0.5,
+            local,
+            remote,
+            local_channels: RwLock::new(Arc::new(Vec::new())),
+        }
+    }
+
+    /// Set the instance tag key of the zone, default is [`ZONE_TAG`].
+    pub fn with_zone_tag(mut self, zone_tag: impl Into<FastStr>) -> Self {
+        self.zone_tag = zone_tag.into();
+        self
+    }
+
+    /// Set the ratio of the healthy local weight under which the calls spill over to the other zones, default is 0.5.
+    pub fn with_min_healthy_ratio(mut self, min_healthy_ratio: f64) -> Self {
+        self.min_healthy_ratio = min_healthy_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set the error rate from which a local channel is unhealthy, default is 0.5.
+    pub fn with_max_error_rate(mut self, max_error_rate: f64) -> Self {
+        self.max_error_rate = max_error_rate;
+        self
+    }
+
+    fn is_local(&self, channel: &RpcChannel<S>) -> bool {
+        match &self.zone {
+            Some(zone) => channel.config().instance.tags.get(self.zone_tag.as_str()).is_some_and(|tag| tag == zone.as_str()),
+            None => true,
+        }
+    }
+
+    /// Returns whether the call goes to the local zone first.
+    fn prefer_local(&self) -> bool {
+        let (mut healthy, mut total) = (0u64, 0u64);
+        for channel in self.local_channels.read().unwrap().iter() {
+            let weight = channel.config().instance.weight as u64;
+            total += weight;
+            if channel.stats().error_rate() < self.max_error_rate {
+                healthy += weight;
+            }
+        }
+        if healthy == 0 {
+            return false;
+        }
+        let ratio = healthy as f64 / total as f64;
+        ratio >= self.min_healthy_ratio || rand::random::<f64>() * self.min_healthy_ratio < ratio
+    }
+
+    /// Splits the change into the changes of the local zone and of the other zones.
+    fn split(&self, changes: RpcChange<S>) -> (RpcChange<S>, RpcChange<S>) {
+        let was_local: HashSet<Address> = self.local_channels.read().unwrap().iter().map(|channel| channel.config().instance.address.clone()).collect();
+        let empty = || RpcChange {
+            all: Vec::new(),
+            added: Vec::new(),
+            updated: Vec::new(),
+            removed: Vec::new(),
+        };
+        let (mut local, mut remote) = (empty(), empty());
+        for channel in changes.all {
+            if self.is_local(&channel) { &mut local } else { &mut remote }.all.push(channel);
+        }
+        for channel in changes.added {
+            if self.is_local(&channel) { &mut local } else { &mut remote }.added.push(channel);
+        }
+        for channel in changes.updated {
+            let address = channel.config().instance.address.clone();
+            match (was_local.contains(&address), self.is_local(&channel)) {
+                (true, true) => local.updated.push(channel),
+                (false, false) => remote.updated.push(channel),
+                // The zone tag of the instance changed.
+                (true, false) => {
+                    local.removed.push(address);
+                    remote.added.push(channel);
+                },
+                (false, true) => {
+                    remote.removed.push(address);
+                    local.added.push(channel);
+                },
+            }
+        }
+        for address in changes.removed {
+            if was_local.contains(&address) { &mut local } else { &mut remote }.removed.push(address);
+        }
+        (local, remote)
+    }
+}
+
+impl<S, LB> LoadBalance<S> for ZoneAwareBalance<S, LB>
+where
+    S: Serve + 'static,
+    S::Req: Send,
+    S::Resp: Send,
+    LB: LoadBalance<S>,
+{
+    type ChannelIter = Chain<LB::ChannelIter, LB::ChannelIter>;
+    fn start_balance(&self, channels: Vec<RpcChannel<S>>) {
+        let (local, remote): (Vec<_>, Vec<_>) = channels.into_iter().partition(|channel| self.is_local(channel));
+        *self.local_channels.write().unwrap() = Arc::new(local.clone());
+        self.local.start_balance(local);
+        self.remote.start_balance(remote);
+    }
+    fn get_picker(&self) -> Self::ChannelIter {
+        if self.prefer_local() {
+            self.local.get_picker().chain(self.remote.get_picker())
+        } else {
+            self.remote.get_picker().chain(self.local.get_picker())
+        }
+    }
+    fn get_picker_for(&self, ctx: &Context, request: &S::Req) -> Self::ChannelIter {
+        if self.prefer_local() {
+            self.local.get_picker_for(ctx, request).chain(self.remote.get_picker_for(ctx, request))
+        } else {
+            self.remote.get_picker_for(ctx, request).chain(self.local.get_picker_for(ctx, request))
+        }
+    }
+    fn report(&self, channel: &RpcChannel<S>, result: &Result<S::Resp, RpcError>) {
+        if self.is_local(channel) {
+            self.local.report(channel, result)
+        } else {
+            self.remote.report(channel, result)
+        }
+    }
+    fn rebalance(&self, changes: Option<RpcChange<S>>) {
+        let Some(changes) = changes else {
+            *self.local_channels.write().unwrap() = Arc::new(Vec::new());
+            self.local.rebalance(None);
+            self.remote.rebalance(None);
+            return;
+        };
+        let (local, remote) = self.split(changes);
+        *self.local_channels.write().unwrap() = Arc::new(local.all.clone());
+        self.local.rebalance(Some(local));
+        self.remote.rebalance(Some(remote));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::balance::tests::{addresses, channels};
+    use crate::client::balance::RoundRobinBalance;
+    use crate::client::discover::Instance;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn prefer_local_zone() {
+        let (_listeners, channels) = channels(&[1, 1, 1]).await;
+        let channels: Vec<_> = channels
+            .into_iter()
+            .zip(["a", "a", "b"])
+            .map(|(channel, zone)| {
+                let instance = Instance {
+                    tags: [(ZONE_TAG.into(), zone.into())].into_iter().collect(),
+                    ..(*channel.config().instance).clone()
+                };
+                channel.clone_update_instance(Arc::new(instance))
+            })
+            .collect();
+        let mut endpoint = Endpoint::new("health");
+        endpoint.insert::<LocalZone>("a".into());
+        let balance = ZoneAwareBalance::new(&endpoint, RoundRobinBalance::new(), RoundRobinBalance::new());
+        balance.start_balance(channels.clone());
+        let local = addresses(&channels[..2]);
+        let remote = channels[2].config().instance.address.clone();
+        for _ in 0..10 {
+            let picked = addresses(&balance.get_picker().collect::<Vec<_>>());
+            assert!(local.contains(&picked[0]));
+            assert_eq!(picked[2], remote);
+        }
+
+        // The local channels fail, the calls spill over to the other zone.
+        for channel in &channels[..2] {
+            channel.stats().observe(Duration::from_millis(1), true);
+        }
+        assert_eq!(balance.get_picker().next().unwrap().config().instance.address, remote);
+
+        // The remote instance moves to the local zone.
+        let moved = channels[2].clone_update_instance(Arc::new(Instance {
+            tags: [(ZONE_TAG.into(), "a".into())].into_iter().collect(),
+            ..(*channels[2].config().instance).clone()
+        }));
+        balance.rebalance(Some(RpcChange {
+            all: vec![channels[0].clone(), channels[1].clone(), moved.clone()],
+            added: vec![],
+            updated: vec![moved],
+            removed: vec![],
+        }));
+        assert_eq!(balance.local_channels.read().unwrap().len(), 3);
+        assert_eq!(balance.get_picker().count(), 3);
+    }
+}
@@ -9,12 +9,18 @@ use crate::client::core::stub::Stub;
 use crate::client::core::{Channel, Config, RpcError};
 use crate::client::discover::Instance;
 use crate::context;
+#[cfg(target_family = "unix")]
+use crate::net::address::connect_unix;
 use crate::net::Address;
 use crate::server::Serve;
 use crate::transport::codec::*;
 use crate::transport::tcp;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+#[cfg(target_family = "unix")]
+use tarpc::tokio_util::codec::LengthDelimitedCodec;
 use tokio::sync::RwLock;
 
 /// Settings that control the behavior of the RPC client.
@@ -99,6 +105,91 @@ impl<S: Serve> Clone for RpcChannel<S> {
 struct InnerRpcChannel<Req, Resp> {
     config: RpcConfig,
     channel: Arc<RwLock<Option<Channel<Req, Resp>>>>,
+    stats: Arc<ChannelStats>,
+}
+
+/// Time constant of the moving averages of [`ChannelStats`].
+const EWMA_DECAY: Duration = Duration::from_secs(10);
+
+/// Load statistics of an [`RpcChannel`], which are maintained by its calls and read by the load balances.
+#[derive(Debug, Default)]
+pub struct ChannelStats {
+    in_flight: AtomicUsize,
+    ewma: Mutex<Option<Ewma>>,
+}
+
+/// Exponentially weighted moving averages of the calls.
+#[derive(Debug)]
+struct Ewma {
+    /// Peak latency in nanoseconds.
+    latency: f64,
+    /// Ratio of the failed calls.
+    errors: f64,
+    stamp: Instant,
+}
+
+impl ChannelStats {
+    /// Returns the number of calls in flight.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Returns the moving average of the call latency, which jumps to any higher latency at once,
+    /// and decays over 10s without calls. Returns `None` before the first call completes.
+    pub fn latency(&self) -> Option<Duration> {
+        let ewma = self.ewma.lock().unwrap();
+        let ewma = ewma.as_ref()?;
+        Some(Duration::from_nanos((ewma.latency * decay(ewma.stamp.elapsed())) as u64))
+    }
+
+    /// Returns the moving average of the ratio of the failed calls, which decays over 10s without calls.
+    pub fn error_rate(&self) -> f64 {
+        self.ewma.lock().unwrap().as_ref().map_or(0.0, |ewma| ewma.errors * decay(ewma.stamp.elapsed()))
+    }
+
+    /// Records a completed call.
+    pub(crate) fn observe(&self, latency: Duration, failed: bool) {
+        let latency = latency.as_nanos() as f64;
+        let failed = if failed { 1.0 } else { 0.0 };
+        let now = Instant::now();
+        let mut ewma = self.ewma.lock().unwrap();
+        match &mut *ewma {
+            Some(ewma) => {
+                let weight = decay(now.saturating_duration_since(ewma.stamp));
+                ewma.latency = if latency > ewma.latency { latency } else { ewma.latency * weight + latency * (1.0 - weight) };
+                ewma.errors = ewma.errors * weight + failed * (1.0 - weight);
+                ewma.stamp = now;
+            },
+            None => {
+                *ewma = Some(Ewma {
+                    latency,
+                    errors: failed,
+                    stamp: now,
+                })
+            },
+        }
+    }
+}
+
+/// Returns the weight left to a measurement after the elapsed time.
+fn decay(elapsed: Duration) -> f64 {
+    (-elapsed.as_secs_f64() / EWMA_DECAY.as_secs_f64()).exp()
+}
+
+/// Counts a call in flight until it is dropped, also when the call is cancelled.
+struct InFlight<'a>(&'a ChannelStats);
+
+impl<'a> InFlight<'a> {
+    fn start(stats: &'a ChannelStats) -> Self {
+        stats.in_flight.fetch_add(1, Ordering::AcqRel);
+        Self(stats)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
 }
 
 impl<S> Debug for RpcChannel<S>
@@ -118,7 +209,11 @@ where
     Resp: Debug,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("InnerRpcChannel").field("config", &self.config).field("channel", &self.channel).finish()
+        f.debug_struct("InnerRpcChannel")
+            .field("config", &self.config)
+            .field("channel", &self.channel)
+            .field("stats", &self.stats)
+            .finish()
     }
 }
 
@@ -127,11 +222,14 @@ impl<S: Serve> Stub for RpcChannel<S> {
     type Resp = S::Resp;
 
     async fn call(&self, ctx: context::Context, request: Self::Req) -> Result<Self::Resp, RpcError> {
+        let _in_flight = InFlight::start(&self.inner.stats);
+        let start = Instant::now();
         let res = if let Some(channel) = &*self.inner.channel.read().await {
             channel.call(ctx, request).await
         } else {
             Err(RpcError::Shutdown)
         };
+        self.inner.stats.observe(start.elapsed(), res.is_err());
         if let Err(RpcError::Shutdown) = res {
             self.inner.channel.write().await.take();
         }
@@ -144,6 +242,20 @@ impl<S: Serve> RpcChannel<S> {
     pub fn config(&self) -> &RpcConfig {
         &self.inner.config
     }
+    /// Returns the load statistics.
+    pub fn stats(&self) -> &ChannelStats {
+        &self.inner.stats
+    }
+
+    pub(crate) fn clone_update_instance(&self, instance: Arc<Instance>) -> Self {
+        let mut inner = InnerRpcChannel {
+            config: self.config().clone(),
+            channel: self.inner.channel.clone(),
+            stats: self.inner.stats.clone(),
+        };
+        inner.config.instance = instance;
+        Self { inner: Arc::new(inner) }
+    }
 }
 
 impl<S> RpcChannel<S>
@@ -159,41 +271,39 @@ where
             inner: Arc::new(InnerRpcChannel {
                 config,
                 channel: Arc::new(RwLock::new(Some(channe))),
+                stats: Default::default(),
             }),
         })
     }
 
     async fn new_channel(config: &RpcConfig) -> Result<Channel<S::Req, S::Resp>, anyhow::Error> {
-        let Address::Ip(address) = &config.instance.address else {
-            anyhow::bail!("invalid address {}", &config.instance.address)
-        };
+        macro_rules! spawn_channel {
+            ($codec_fn:expr) => {
+                match &config.instance.address {
+                    Address::Ip(address) => {
+                        let mut conn = tcp::connect(address, $codec_fn);
+                        conn.config_mut().max_frame_length(config.max_frame_len);
+                        Ok(tarpc::client::new(config.core_config.clone(), conn.await?).spawn())
+                    },
+                    #[cfg(target_family = "unix")]
+                    Address::Unix(address) => {
+                        let framed = LengthDelimitedCodec::builder().max_frame_length(config.max_frame_len).new_framed(connect_unix(address).await?);
+                        Ok(tarpc::client::new(config.core_config.clone(), crate::transport::new(framed, $codec_fn())).spawn())
+                    },
+                }
+            };
+        }
         match config.transport_codec {
-            Codec::Bincode => {
-                // Bincode codec using [bincode](https://docs.rs/bincode) crate.
-                let mut conn = tcp::connect(address, Bincode::default);
-                conn.config_mut().max_frame_length(config.max_frame_len);
-                Ok(tarpc::client::new(config.core_config.clone(), conn.await?).spawn())
-            },
-            Codec::Json => {
-                // JSON codec using [serde_json](https://docs.rs/serde_json) crate.
-                let mut conn = tcp::connect(address, Json::default);
-                conn.config_mut().max_frame_length(config.max_frame_len);
-                Ok(tarpc::client::new(config.core_config.clone(), conn.await?).spawn())
-            },
+            // Bincode codec using [bincode](https://docs.rs/bincode) crate.
+            Codec::Bincode => spawn_channel!(Bincode::default),
+            // JSON codec using [serde_json](https://docs.rs/serde_json) crate.
+            Codec::Json => spawn_channel!(Json::default),
+            // MessagePack codec using [rmp-serde](https://docs.rs/rmp-serde) crate.
             #[cfg(feature = "serde-transport-messagepack")]
-            Codec::MessagePack => {
-                // MessagePack codec using [rmp-serde](https://docs.rs/rmp-serde) crate.
-                let mut conn = tcp::connect(address, MessagePack::default);
-                conn.config_mut().max_frame_length(config.max_frame_len);
-                Ok(tarpc::client::new(config.core_config.clone(), conn.await?).spawn())
-            },
+            Codec::MessagePack => spawn_channel!(MessagePack::default),
+            // CBOR codec using [serde_cbor](https://docs.rs/serde_cbor) crate.
             #[cfg(feature = "serde-transport-cbor")]
-            Codec::Cbor => {
-                // CBOR codec using [serde_cbor](https://docs.rs/serde_cbor) crate.
-                let mut conn = tcp::connect(address, Cbor::default);
-                conn.config_mut().max_frame_length(config.max_frame_len);
-                Ok(tarpc::client::new(config.core_config.clone(), conn.await?).spawn())
-            },
+            Codec::Cbor => spawn_channel!(Cbor::default),
         }
     }
 
@@ -202,13 +312,4 @@ where
         self.inner.channel.write().await.replace(channel);
         Ok(())
     }
-
-    pub(crate) fn clone_update_instance(&self, instance: Arc<Instance>) -> Self {
-        let mut inner = InnerRpcChannel {
-            config: self.config().clone(),
-            channel: self.inner.channel.clone(),
-        };
-        inner.config.instance = instance;
-        Self { inner: Arc::new(inner) }
-    }
 }
@@ -0,0 +1,92 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! Key-scoped broadcast channels of discovery changes.
+
+use super::Discovery;
+use async_broadcast::{Receiver, Sender};
+use faststr::FastStr;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Default capacity of a subscription channel.
+const DEFAULT_CAPACITY: usize = 64;
+
+/// [`DiscoveryBroadcaster`] pushes [`Discovery`] changes to the receivers returned by [`super::Discover::watch`],
+/// following the key-scoped contract of the trait.
+///
+/// Every subscription has its own channel, which only carries the discoveries of the subscribed keys.
+/// The channels never block the sender: when a receiver lags behind, the oldest messages are dropped
+/// and the receiver observes [`async_broadcast::RecvError::Overflowed`].
+/// A channel is released as soon as all of its receivers are dropped.
+#[derive(Default)]
+pub struct DiscoveryBroadcaster {
+    subscriptions: Mutex<Vec<Subscription>>,
+}
+
+struct Subscription {
+    /// `None` subscribes all keys.
+    keys: Option<HashSet<FastStr>>,
+    sender: Sender<Discovery>,
+}
+
+impl DiscoveryBroadcaster {
+    /// Creates a new [`DiscoveryBroadcaster`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a new receiver of the discoveries of the keys, or of all keys if `keys` is `None`.
+    pub fn subscribe(&self, keys: Option<&[FastStr]>) -> Receiver<Discovery> {
+        let (mut sender, receiver) = async_broadcast::broadcast(DEFAULT_CAPACITY);
+        sender.set_overflow(true);
+        sender.set_await_active(false);
+        self.subscriptions.lock().unwrap().push(Subscription {
+            keys: keys.map(|keys| keys.iter().cloned().collect()),
+            sender,
+        });
+        receiver
+    }
+
+    /// Sends the discovery to the receivers that subscribed its key.
+    pub fn send(&self, discovery: Discovery) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|subscription| !subscription.sender.is_closed());
+        for subscription in subscriptions.iter() {
+            if subscription.keys.as_ref().map_or(true, |keys| keys.contains(&discovery.key)) {
+                let _ = subscription.sender.try_broadcast(discovery.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::discover::InstanceCluster;
+
+    #[test]
+    fn key_scoped_subscriptions() {
+        let broadcaster = DiscoveryBroadcaster::new();
+        let mut all = broadcaster.subscribe(None);
+        let mut hello = broadcaster.subscribe(Some(&["hello".into()]));
+        let world = broadcaster.subscribe(Some(&["world".into()]));
+        drop(world);
+        broadcaster.send(Discovery {
+            key: "world".into(),
+            instance_cluster: InstanceCluster::Lpc,
+        });
+        assert_eq!(broadcaster.subscriptions.lock().unwrap().len(), 2);
+        broadcaster.send(Discovery {
+            key: "hello".into(),
+            instance_cluster: InstanceCluster::Lpc,
+        });
+        assert_eq!(all.try_recv().unwrap().key, "world");
+        assert_eq!(all.try_recv().unwrap().key, "hello");
+        assert_eq!(hello.try_recv().unwrap().key, "hello");
+        assert!(hello.try_recv().is_err());
+    }
+}
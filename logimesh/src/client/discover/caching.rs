@@ -0,0 +1,315 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! Caching discover wrapper.
+
+use super::{Discover, Discovery, DiscoveryBroadcaster, InstanceCluster};
+use crate::client::ClientError;
+use crate::component::Endpoint;
+use async_broadcast::Receiver;
+use faststr::FastStr;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Default time a cached discovery stays fresh.
+const DEFAULT_TTL: Duration = Duration::from_secs(30);
+/// Default time before the first retry of a failed refresh.
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// [`CachingDiscover`] memoizes the [`Discovery`] of the inner discover per endpoint key for a TTL.
+///
+/// Only the first [`Discover::discover`] of a key waits for the inner discover. Then the key is refreshed in the
+/// background every TTL, and a stale key is served the last good result while it is being refreshed. A failed refresh
+/// keeps serving the last good result, so that a blip of the backend neither fails
+/// [`crate::client::lrcall::Builder::try_spawn`] nor empties the instance list, and it is retried after a backoff,
+/// which doubles with every failure up to the TTL.
+///
+/// Changes found by refreshes and reported by the inner [`Discover::watch`] are pushed through [`Discover::watch`].
+pub struct CachingDiscover<D> {
+    inner: Arc<Inner<D>>,
+    timing: Timing,
+}
+
+#[derive(Clone, Copy)]
+struct Timing {
+    ttl: Duration,
+    retry_backoff: Duration,
+}
+
+impl Timing {
+    /// Returns the time before the next refresh after the consecutive failures.
+    fn retry_after(&self, failures: u32) -> Duration {
+        let backoff = self.retry_backoff.saturating_mul(1 << failures.saturating_sub(1).min(16));
+        backoff.min(self.ttl.max(self.retry_backoff))
+    }
+}
+
+struct Inner<D> {
+    discover: D,
+    cache: Mutex<HashMap<FastStr, Entry>>,
+    broadcaster: DiscoveryBroadcaster,
+    watching: AtomicBool,
+}
+
+struct Entry {
+    instance_cluster: InstanceCluster,
+    /// When the entry gets stale, it is pushed forward when a refresh starts, so that it runs once at a time.
+    refresh_at: Instant,
+    failures: u32,
+}
+
+impl<D> Clone for CachingDiscover<D> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            timing: self.timing,
+        }
+    }
+}
+
+impl<D: Discover> CachingDiscover<D> {
+    /// Creates a new [`CachingDiscover`] wrapping the discover.
+    pub fn new(discover: D) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                discover,
+                cache: Mutex::new(HashMap::new()),
+                broadcaster: DiscoveryBroadcaster::new(),
+                watching: AtomicBool::new(false),
+            }),
+            timing: Timing {
+                ttl: DEFAULT_TTL,
+                retry_backoff: DEFAULT_RETRY_BACKOFF,
+            },
+        }
+    }
+
+    /// Set the time a cached discovery stays fresh, default is 30s.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.timing.ttl = ttl;
+        self
+    }
+
+    /// Set the time before the first retry of a failed refresh, default is 1s.
+    pub fn with_retry_backoff(mut self, retry_backoff: Duration) -> Self {
+        self.timing.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// Returns the wrapped discover.
+    pub fn get_ref(&self) -> &D {
+        &self.inner.discover
+    }
+
+    /// Subscribes the inner discover, and applies its changes in the background.
+    fn start_watching(&self) {
+        if self.inner.watching.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            self.inner.watching.store(false, Ordering::Release);
+            return;
+        };
+        if let Some(mut receiver) = self.inner.discover.watch(None) {
+            let weak = Arc::downgrade(&self.inner);
+            let ttl = self.timing.ttl;
+            runtime.spawn(async move {
+                while let Some(discovery) = receiver.next().await {
+                    let Some(inner) = weak.upgrade() else {
+                        return;
+                    };
+                    inner.store(discovery, ttl, false);
+                }
+            });
+        }
+    }
+
+    /// Refreshes the key of the endpoint in the background every TTL, as long as the discover is alive.
+    fn spawn_refreshing(&self, endpoint: Endpoint) {
+        let (weak, timing) = (Arc::downgrade(&self.inner), self.timing);
+        tokio::spawn(async move {
+            let key = endpoint.key();
+            loop {
+                let Some(refresh_at) = weak.upgrade().and_then(|inner| inner.cache.lock().unwrap().get(&key).map(|entry| entry.refresh_at)) else {
+                    return;
+                };
+                tokio::time::sleep_until(refresh_at.into()).await;
+                let Some(inner) = weak.upgrade() else {
+                    return;
+                };
+                if inner.start_refresh(&key, timing.ttl) {
+                    inner.refresh(&endpoint, timing).await;
+                }
+            }
+        });
+    }
+}
+
+impl<D> Inner<D> {
+    /// Caches the discovery and broadcasts it if it changed, `insert` also caches a new key.
+    /// Returns whether the key is new.
+    fn store(&self, discovery: Discovery, ttl: Duration, insert: bool) -> bool {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            match cache.get_mut(&discovery.key) {
+                Some(entry) => {
+                    entry.refresh_at = Instant::now() + ttl;
+                    entry.failures = 0;
+                    if entry.instance_cluster == discovery.instance_cluster {
+                        return false;
+                    }
+                    entry.instance_cluster = discovery.instance_cluster.clone();
+                },
+                None if insert => {
+                    cache.insert(
+                        discovery.key.clone(),
+                        Entry {
+                            instance_cluster: discovery.instance_cluster.clone(),
+                            refresh_at: Instant::now() + ttl,
+                            failures: 0,
+                        },
+                    );
+                    return true;
+                },
+                None => return false,
+            }
+        }
+        self.broadcaster.send(discovery);
+        false
+    }
+
+    /// Returns whether the cached key is stale, and pushes its refresh time forward by the TTL,
+    /// so that the caller is the only one which refreshes it.
+    fn start_refresh(&self, key: &FastStr, ttl: Duration) -> bool {
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        match cache.get_mut(key) {
+            Some(entry) if now >= entry.refresh_at => {
+                entry.refresh_at = now + ttl;
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+impl<D: Discover> Inner<D> {
+    /// Refreshes the discovery of the endpoint, and backs off the next refresh when it fails.
+    async fn refresh(&self, endpoint: &Endpoint, timing: Timing) {
+        match self.discover.discover(endpoint).await {
+            Ok(discovery) => {
+                self.store(discovery, timing.ttl, false);
+            },
+            Err(e) => {
+                let key = endpoint.key();
+                warn!("[LOGIMESH] failed to refresh the discovery of {key}, serving the stale one: {e}");
+                if let Some(entry) = self.cache.lock().unwrap().get_mut(&key) {
+                    entry.failures = entry.failures.saturating_add(1);
+                    entry.refresh_at = Instant::now() + timing.retry_after(entry.failures);
+                }
+            },
+        }
+    }
+}
+
+impl<D: Discover> Discover for CachingDiscover<D> {
+    fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send {
+        async move {
+            self.start_watching();
+            let key = endpoint.key();
+            let cached = self.inner.cache.lock().unwrap().get(&key).map(|entry| entry.instance_cluster.clone());
+            if let Some(instance_cluster) = cached {
+                if self.inner.start_refresh(&key, self.timing.ttl) {
+                    let (inner, endpoint, timing) = (self.inner.clone(), endpoint.clone(), self.timing);
+                    tokio::spawn(async move { inner.refresh(&endpoint, timing).await });
+                }
+                return Ok(Discovery { key, instance_cluster });
+            }
+            let discovery = self.inner.discover.discover(endpoint).await?;
+            if self.inner.store(discovery.clone(), self.timing.ttl, true) {
+                self.spawn_refreshing(endpoint.clone());
+            }
+            Ok(discovery)
+        }
+    }
+
+    fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+        self.start_watching();
+        Some(self.inner.broadcaster.subscribe(keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::discover::{FixedDiscover, Instance};
+    use std::sync::atomic::AtomicUsize;
+
+    struct FlakyDiscover {
+        discover: FixedDiscover,
+        down: AtomicBool,
+        calls: AtomicUsize,
+    }
+
+    impl Discover for FlakyDiscover {
+        async fn discover(&self, endpoint: &Endpoint) -> Result<Discovery, ClientError> {
+            self.calls.fetch_add(1, Ordering::AcqRel);
+            if self.down.load(Ordering::Acquire) {
+                return Err(ClientError::Discover("registry is down".into()));
+            }
+            self.discover.discover(endpoint).await
+        }
+
+        fn watch(&self, _: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+            None
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_stale_and_push_refreshes() {
+        let fixed = FixedDiscover::new(InstanceCluster::Lpc);
+        let discover = CachingDiscover::new(FlakyDiscover {
+            discover: fixed.clone(),
+            down: AtomicBool::new(false),
+            calls: AtomicUsize::new(0),
+        })
+        .with_ttl(Duration::from_millis(50))
+        .with_retry_backoff(Duration::from_millis(200));
+        let mut receiver = discover.watch(None).unwrap();
+        let endpoint = Endpoint::new("hello");
+        assert_eq!(discover.discover(&endpoint).await.unwrap().instance_cluster, InstanceCluster::Lpc);
+        assert_eq!(discover.discover(&endpoint).await.unwrap().instance_cluster, InstanceCluster::Lpc);
+        assert_eq!(discover.get_ref().calls.load(Ordering::Acquire), 1);
+
+        // The backend fails the refresh in the background, the stale discovery is served,
+        // and the next refresh backs off.
+        discover.get_ref().down.store(true, Ordering::Release);
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert_eq!(discover.get_ref().calls.load(Ordering::Acquire), 2);
+        assert_eq!(discover.discover(&endpoint).await.unwrap().instance_cluster, InstanceCluster::Lpc);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(discover.get_ref().calls.load(Ordering::Acquire), 2);
+        assert!(discover.discover(&Endpoint::new("other")).await.is_err());
+
+        // The backend is back with a change, which the next refresh pushes through watch.
+        let instances = vec![Arc::new(Instance {
+            address: "127.0.0.1:8000".parse().unwrap(),
+            weight: 1,
+            tags: Default::default(),
+        })];
+        fixed.handle().replace(InstanceCluster::Rpc(instances.clone()));
+        discover.get_ref().down.store(false, Ordering::Release);
+        let discovery = tokio::time::timeout(Duration::from_secs(1), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(discovery.key, "hello");
+        assert_eq!(discovery.instance_cluster, InstanceCluster::Rpc(instances.clone()));
+        assert_eq!(discover.discover(&endpoint).await.unwrap().instance_cluster, InstanceCluster::Rpc(instances));
+    }
+}
@@ -0,0 +1,336 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! Discover combinators.
+
+use super::{Discover, Discovery, DiscoveryBroadcaster, Instance, InstanceCluster};
+use crate::client::ClientError;
+use crate::component::Endpoint;
+use async_broadcast::Receiver;
+use faststr::FastStr;
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// How two instance clusters are combined.
+#[derive(Clone, Copy)]
+enum Strategy {
+    First,
+    Merge,
+    Fallback,
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    First,
+    Second,
+}
+
+/// The shared implementation of the combinators.
+///
+/// The last instance cluster reported by each discover is kept per endpoint key, and the combined result
+/// is pushed through [`Discover::watch`] whenever a change of either discover changes it.
+struct Composite<A, B> {
+    inner: Arc<Inner<A, B>>,
+}
+
+struct Inner<A, B> {
+    first: A,
+    second: B,
+    strategy: Strategy,
+    state: Mutex<HashMap<FastStr, KeyState>>,
+    broadcaster: DiscoveryBroadcaster,
+    watching: AtomicBool,
+}
+
+#[derive(Default)]
+struct KeyState {
+    first: Option<InstanceCluster>,
+    second: Option<InstanceCluster>,
+    resolved: Option<InstanceCluster>,
+}
+
+impl<A, B> Clone for Composite<A, B> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl Strategy {
+    fn resolve(self, first: Option<&InstanceCluster>, second: Option<&InstanceCluster>) -> Option<InstanceCluster> {
+        match self {
+            Strategy::First => match (first, second) {
+                (Some(cluster), _) if !is_empty(cluster) => Some(cluster.clone()),
+                (_, Some(cluster)) => Some(cluster.clone()),
+                (cluster, None) => cluster.cloned(),
+            },
+            Strategy::Fallback => first.or(second).cloned(),
+            Strategy::Merge => {
+                let mut lpc = false;
+                let mut rpc_percent = 0;
+                let mut merged: Option<Vec<Arc<Instance>>> = None;
+                for cluster in [first, second].into_iter().flatten() {
+                    match cluster.instances() {
+                        None => lpc = true,
+                        Some(instances) => {
+                            rpc_percent = rpc_percent.max(cluster.rpc_percent());
+                            let merged = merged.get_or_insert_with(Vec::new);
+                            for instance in instances {
+                                if !merged.iter().any(|old| old.address == instance.address) {
+                                    merged.push(instance.clone());
+                                }
+                            }
+                        },
+                    }
+                }
+                match merged {
+                    Some(instances) => Some(InstanceCluster::split(rpc_percent, instances)),
+                    None if lpc => Some(InstanceCluster::Lpc),
+                    None => None,
+                }
+            },
+        }
+    }
+}
+
+fn is_empty(instance_cluster: &InstanceCluster) -> bool {
+    instance_cluster.instances().is_some_and(|instances| instances.is_empty())
+}
+
+impl<A: Discover, B: Discover> Composite<A, B> {
+    fn new(first: A, second: B, strategy: Strategy) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                first,
+                second,
+                strategy,
+                state: Mutex::new(HashMap::new()),
+                broadcaster: DiscoveryBroadcaster::new(),
+                watching: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Subscribes both discovers, and forwards their changes in the background.
+    fn start_watching(&self) {
+        if self.inner.watching.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            self.inner.watching.store(false, Ordering::Release);
+            return;
+        };
+        let mut streams: Vec<BoxStream<'static, (Side, Discovery)>> = Vec::new();
+        if let Some(receiver) = self.inner.first.watch(None) {
+            streams.push(receiver.map(|discovery| (Side::First, discovery)).boxed());
+        }
+        if let Some(receiver) = self.inner.second.watch(None) {
+            streams.push(receiver.map(|discovery| (Side::Second, discovery)).boxed());
+        }
+        if !streams.is_empty() {
+            runtime.spawn(forward(Arc::downgrade(&self.inner), stream::select_all(streams)));
+        }
+    }
+}
+
+impl<A: Discover, B: Discover> Inner<A, B> {
+    /// Applies a change of one discover, and broadcasts the combined result if it changed.
+    fn update(&self, side: Side, discovery: Discovery) {
+        let discovery = {
+            let mut state = self.state.lock().unwrap();
+            // Only the discovered keys are tracked.
+            let Some(key_state) = state.get_mut(&discovery.key) else {
+                return;
+            };
+            match side {
+                Side::First => key_state.first = Some(discovery.instance_cluster),
+                Side::Second => key_state.second = Some(discovery.instance_cluster),
+            }
+            let resolved = self.strategy.resolve(key_state.first.as_ref(), key_state.second.as_ref());
+            if resolved.is_none() || resolved == key_state.resolved {
+                return;
+            }
+            key_state.resolved = resolved.clone();
+            Discovery {
+                key: discovery.key,
+                instance_cluster: resolved.unwrap(),
+            }
+        };
+        self.broadcaster.send(discovery);
+    }
+}
+
+async fn forward<A: Discover, B: Discover>(weak: Weak<Inner<A, B>>, mut changes: impl futures::Stream<Item = (Side, Discovery)> + Unpin) {
+    while let Some((side, discovery)) = changes.next().await {
+        let Some(inner) = weak.upgrade() else {
+            return;
+        };
+        inner.update(side, discovery);
+    }
+}
+
+impl<A: Discover, B: Discover> Discover for Composite<A, B> {
+    fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send {
+        async move {
+            // Subscribe first, so that no change after the discovery is missed.
+            self.start_watching();
+            let key = endpoint.key();
+            let (first, second) = futures::join!(self.inner.first.discover(endpoint), self.inner.second.discover(endpoint));
+            let mut errors = Vec::new();
+            let mut state = self.inner.state.lock().unwrap();
+            let key_state = state.entry(key.clone()).or_default();
+            match first {
+                Ok(discovery) => key_state.first = Some(discovery.instance_cluster),
+                Err(e) => errors.push(e.to_string()),
+            }
+            match second {
+                Ok(discovery) => key_state.second = Some(discovery.instance_cluster),
+                Err(e) => errors.push(e.to_string()),
+            }
+            key_state.resolved = self.inner.strategy.resolve(key_state.first.as_ref(), key_state.second.as_ref());
+            match key_state.resolved.clone() {
+                Some(instance_cluster) => Ok(Discovery { key, instance_cluster }),
+                None => {
+                    state.remove(&key);
+                    Err(ClientError::Discover(errors.join("; ").into()))
+                },
+            }
+        }
+    }
+
+    fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+        self.start_watching();
+        Some(self.inner.broadcaster.subscribe(keys))
+    }
+}
+
+macro_rules! combinator {
+    ($(#[$attr:meta])* $name:ident, $strategy:expr, $first:ident, $second:ident) => {
+        $(#[$attr])*
+        pub struct $name<A, B>(Composite<A, B>);
+
+        impl<A, B> Clone for $name<A, B> {
+            fn clone(&self) -> Self {
+                Self(self.0.clone())
+            }
+        }
+
+        impl<A: Discover, B: Discover> $name<A, B> {
+            #[doc = concat!("Creates a new [`", stringify!($name), "`].")]
+            pub fn new($first: A, $second: B) -> Self {
+                Self(Composite::new($first, $second, $strategy))
+            }
+        }
+
+        impl<A: Discover, B: Discover> Discover for $name<A, B> {
+            fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send {
+                self.0.discover(endpoint)
+            }
+
+            fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+                self.0.watch(keys)
+            }
+        }
+    };
+}
+
+combinator!(
+    /// [`FirstDiscover`] returns the instance cluster of the first discover, unless it fails or has no instance,
+    /// in which case the instance cluster of the second discover is returned.
+    ///
+    /// Nest it to combine more than two discovers.
+    FirstDiscover,
+    Strategy::First,
+    first,
+    second
+);
+
+combinator!(
+    /// [`MergeDiscover`] merges the instance lists of two discovers, and ignores the one that fails.
+    ///
+    /// Instances are deduplicated by address, keeping the instance of the first discover.
+    /// [`InstanceCluster::Lpc`] is returned only if no discover returns an instance list,
+    /// and the highest percentage of remote calls of the merged ones applies, see [`InstanceCluster::Split`].
+    MergeDiscover,
+    Strategy::Merge,
+    first,
+    second
+);
+
+combinator!(
+    /// [`FallbackDiscover`] returns the instance cluster of the primary discover, and only falls back to the other one
+    /// while the primary has never succeeded for the endpoint key, e.g. a registry with a [`super::FixedDiscover`] safety net.
+    ///
+    /// The primary is followed again as soon as its [`Discover::watch`] reports the key,
+    /// and its last known instance cluster is kept when it fails afterwards.
+    FallbackDiscover,
+    Strategy::Fallback,
+    primary,
+    fallback
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::discover::FixedDiscover;
+    use std::time::Duration;
+
+    struct FailingDiscover;
+
+    impl Discover for FailingDiscover {
+        async fn discover(&self, _: &Endpoint) -> Result<Discovery, ClientError> {
+            Err(ClientError::Discover("registry is down".into()))
+        }
+
+        fn watch(&self, _: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+            None
+        }
+    }
+
+    fn instances(addresses: &[&str]) -> Vec<Arc<Instance>> {
+        addresses
+            .iter()
+            .map(|address| {
+                Arc::new(Instance {
+                    address: address.parse().unwrap(),
+                    weight: 1,
+                    tags: Default::default(),
+                })
+            })
+            .collect()
+    }
+
+    async fn recv(receiver: &mut Receiver<Discovery>) -> InstanceCluster {
+        tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap().instance_cluster
+    }
+
+    #[tokio::test]
+    async fn combinators() {
+        let endpoint = Endpoint::new("hello");
+        let fallback = FallbackDiscover::new(FailingDiscover, FixedDiscover::from_address_str(vec!["127.0.0.1:8000"]).unwrap());
+        assert_eq!(fallback.discover(&endpoint).await.unwrap().instance_cluster, InstanceCluster::Rpc(instances(&["127.0.0.1:8000"])));
+        assert!(FallbackDiscover::new(FailingDiscover, FailingDiscover).discover(&endpoint).await.is_err());
+
+        let primary = FixedDiscover::new(InstanceCluster::Rpc(vec![]));
+        let first = FirstDiscover::new(primary.clone(), FixedDiscover::from_address_str(vec!["127.0.0.1:8000"]).unwrap());
+        let mut receiver = first.watch(Some(&["hello".into()])).unwrap();
+        assert_eq!(first.discover(&endpoint).await.unwrap().instance_cluster, InstanceCluster::Rpc(instances(&["127.0.0.1:8000"])));
+        primary.handle().replace_key("hello", InstanceCluster::Rpc(instances(&["127.0.0.2:8000"])));
+        assert_eq!(recv(&mut receiver).await, InstanceCluster::Rpc(instances(&["127.0.0.2:8000"])));
+
+        let second = FixedDiscover::from_address_str(vec!["127.0.0.1:8000", "127.0.0.2:8000"]).unwrap();
+        let merge = MergeDiscover::new(FixedDiscover::from_address_str(vec!["127.0.0.1:8000"]).unwrap(), second.clone());
+        let mut receiver = merge.watch(None).unwrap();
+        assert_eq!(
+            merge.discover(&endpoint).await.unwrap().instance_cluster,
+            InstanceCluster::Rpc(instances(&["127.0.0.1:8000", "127.0.0.2:8000"]))
+        );
+        second.handle().replace_key("hello", InstanceCluster::Lpc);
+        assert_eq!(recv(&mut receiver).await, InstanceCluster::Rpc(instances(&["127.0.0.1:8000"])));
+    }
+}
@@ -0,0 +1,435 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! Discover backed by the Consul health API.
+
+use super::{Discover, Discovery, DiscoveryBroadcaster, Instance, InstanceCluster};
+use crate::client::ClientError;
+use crate::component::Endpoint;
+use crate::net::http;
+use async_broadcast::Receiver;
+use faststr::FastStr;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tracing::warn;
+
+/// Default time a blocking query waits for changes on Consul.
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+/// Time to wait before retrying a failed blocking query.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// [`Endpoint`] tag of the Consul service tags that instances must have, separated by commas.
+///
+/// # Example:
+/// ```
+/// use logimesh::client::discover::ConsulTags;
+/// use logimesh::component::Endpoint;
+///
+/// let mut endpoint = Endpoint::new("hello");
+/// endpoint.insert::<ConsulTags>("primary,v2".into());
+/// ```
+pub struct ConsulTags;
+
+/// [`Endpoint`] tag of the Consul datacenter to query, default is the datacenter of the agent.
+pub struct ConsulDatacenter;
+
+/// The HTTP API of a Consul agent.
+#[derive(Clone, Debug)]
+pub(crate) struct ConsulAgent {
+    host: FastStr,
+    token: Option<FastStr>,
+}
+
+impl ConsulAgent {
+    pub(crate) fn new(host: impl Into<FastStr>) -> Self {
+        Self { host: host.into(), token: None }
+    }
+
+    pub(crate) fn set_token(&mut self, token: impl Into<FastStr>) {
+        self.token = Some(token.into());
+    }
+
+    pub(crate) async fn request(&self, method: &str, path: &str, body: &[u8]) -> io::Result<http::Response> {
+        let mut headers = vec![("Content-Type", "application/json")];
+        if let Some(token) = &self.token {
+            headers.push(("X-Consul-Token", token));
+        }
+        http::request(&self.host, method, path, &headers, body).await?.error_for_status()
+    }
+}
+
+/// Returns the Consul service tags declared on the endpoint.
+pub(crate) fn consul_tags(endpoint: &Endpoint) -> Vec<String> {
+    endpoint
+        .get::<ConsulTags>()
+        .map(|tags| tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// [`ConsulDiscover`] discovers the passing instances of [`Endpoint::service_name`] from a Consul agent.
+///
+/// The query is narrowed by the [`ConsulTags`] and [`ConsulDatacenter`] tags of the endpoint.
+/// Every discovered endpoint key is watched in the background with Consul blocking queries,
+/// and its changes are pushed through [`Discover::watch`]. A key has a single watcher, which follows
+/// the query of the last discovered endpoint, so the endpoints of a service which differ by the datacenter
+/// or the tags should have distinct keys, see [`Endpoint::key_maker`].
+///
+/// The Consul service meta becomes the [`Instance::tags`], and so do the Consul service tags:
+/// a tag in the form of `key=value` is split into a key and a value, other tags get an empty value.
+#[derive(Clone)]
+pub struct ConsulDiscover {
+    agent: ConsulAgent,
+    inner: Arc<Inner>,
+    wait: Duration,
+}
+
+struct Inner {
+    broadcaster: DiscoveryBroadcaster,
+    /// The watched keys with the paths of their queries, and the tokens that keep their watchers alive.
+    watching: Mutex<HashMap<FastStr, (String, Arc<()>)>>,
+}
+
+/// A watched query of an endpoint key, which stops once its token is dropped.
+struct Watching {
+    key: FastStr,
+    path: String,
+    token: Weak<()>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceEntry {
+    node: Node,
+    service: AgentService,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Node {
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AgentService {
+    #[serde(default)]
+    address: String,
+    port: u16,
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    meta: Option<HashMap<String, String>>,
+    #[serde(default)]
+    weights: Option<Weights>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Weights {
+    passing: u32,
+}
+
+impl ConsulDiscover {
+    /// Creates a new [`ConsulDiscover`] with the HTTP address of the Consul agent, e.g. `127.0.0.1:8500`.
+    pub fn new(agent_address: impl Into<FastStr>) -> Self {
+        Self {
+            agent: ConsulAgent::new(agent_address),
+            inner: Arc::new(Inner {
+                broadcaster: DiscoveryBroadcaster::new(),
+                watching: Mutex::new(HashMap::new()),
+            }),
+            wait: DEFAULT_WAIT,
+        }
+    }
+
+    /// Set the ACL token sent with every request.
+    pub fn with_token(mut self, token: impl Into<FastStr>) -> Self {
+        self.agent.set_token(token);
+        self
+    }
+
+    /// Set the time a blocking query waits for changes on Consul, default is 30s.
+    pub fn with_wait(mut self, wait: Duration) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    /// Watches the query of the key, the watcher of another query of the key is replaced.
+    fn spawn_watch(&self, key: FastStr, path: String, index: u64, instances: Option<Vec<Arc<Instance>>>) {
+        let token = Arc::new(());
+        {
+            let mut watching = self.inner.watching.lock().unwrap();
+            if watching.get(&key).is_some_and(|(watched, _)| *watched == path) {
+                return;
+            }
+            // Dropping the token of the replaced watcher stops it.
+            watching.insert(key.clone(), (path.clone(), token.clone()));
+        }
+        let watching = Watching {
+            key,
+            path,
+            token: Arc::downgrade(&token),
+        };
+        tokio::spawn(watch(Arc::downgrade(&self.inner), self.agent.clone(), watching, index, instances, self.wait));
+    }
+}
+
+impl Discover for ConsulDiscover {
+    fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send {
+        async move {
+            let key = endpoint.key();
+            let path = health_path(endpoint);
+            match query(&self.agent, &path, 0, None).await {
+                Ok((index, instances)) => {
+                    self.spawn_watch(key.clone(), path, index, Some(instances.clone()));
+                    Ok(Discovery {
+                        key,
+                        instance_cluster: InstanceCluster::Rpc(instances),
+                    })
+                },
+                Err(e) => {
+                    // Keep watching, so that the key is reported once consul is back.
+                    self.spawn_watch(key, path, 0, None);
+                    Err(ClientError::Discover(format!("failed to query consul: {e}").into()))
+                },
+            }
+        }
+    }
+
+    fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+        Some(self.inner.broadcaster.subscribe(keys))
+    }
+}
+
+fn health_path(endpoint: &Endpoint) -> String {
+    let mut path = format!("/v1/health/service/{}?passing=true", http::encode(endpoint.service_name_ref()));
+    if let Some(dc) = endpoint.get::<ConsulDatacenter>() {
+        path.push_str(&format!("&dc={}", http::encode(dc)));
+    }
+    for tag in consul_tags(endpoint) {
+        path.push_str(&format!("&tag={}", http::encode(&tag)));
+    }
+    path
+}
+
+/// Queries the passing instances, and blocks until the index changes or the wait time elapses if `wait` is set.
+async fn query(agent: &ConsulAgent, path: &str, index: u64, wait: Option<Duration>) -> io::Result<(u64, Vec<Arc<Instance>>)> {
+    let response = match wait {
+        Some(wait) => {
+            let path = format!("{path}&index={index}&wait={}ms", wait.as_millis());
+            // Consul adds a random jitter of up to wait/16 to the wait time.
+            tokio::time::timeout(wait + wait / 16 + RETRY_INTERVAL, agent.request("GET", &path, &[]))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "blocking query timed out"))??
+        },
+        None => agent.request("GET", path, &[]).await?,
+    };
+    let index = response.header("X-Consul-Index").and_then(|v| v.parse().ok()).unwrap_or_default();
+    let entries: Vec<ServiceEntry> = serde_json::from_slice(&response.body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((index, entries.into_iter().filter_map(to_instance).map(Arc::new).collect()))
+}
+
+fn to_instance(entry: ServiceEntry) -> Option<Instance> {
+    let host = if entry.service.address.is_empty() { &entry.node.address } else { &entry.service.address };
+    let ip: IpAddr = match host.parse() {
+        Ok(ip) => ip,
+        Err(_) => {
+            warn!("[LOGIMESH] unsupported consul instance address {host}");
+            return None;
+        },
+    };
+    let mut tags = HashMap::new();
+    for tag in entry.service.tags.unwrap_or_default() {
+        match tag.split_once('=') {
+            Some((k, v)) => tags.insert(k.to_string().into(), v.to_string().into()),
+            None => tags.insert(tag.into(), "".into()),
+        };
+    }
+    for (k, v) in entry.service.meta.unwrap_or_default() {
+        tags.insert(k.into(), v.into());
+    }
+    Some(Instance {
+        address: SocketAddr::new(ip, entry.service.port).into(),
+        weight: entry.service.weights.map_or(1, |weights| weights.passing),
+        tags,
+    })
+}
+
+async fn watch(weak: Weak<Inner>, agent: ConsulAgent, watching: Watching, mut index: u64, mut last: Option<Vec<Arc<Instance>>>, wait: Duration) {
+    let Watching { key, path, token } = watching;
+    loop {
+        if weak.strong_count() == 0 || token.strong_count() == 0 {
+            return;
+        }
+        match query(&agent, &path, index, Some(wait)).await {
+            Ok((next_index, instances)) => {
+                // The index is reset when it goes backwards, as recommended by Consul.
+                index = if next_index < index { 0 } else { next_index };
+                // The index also changes with unrelated updates.
+                if last.as_ref() == Some(&instances) {
+                    continue;
+                }
+                let Some(inner) = weak.upgrade() else {
+                    return;
+                };
+                // The token is checked under the lock, so a replaced watcher sends nothing.
+                let _watching = inner.watching.lock().unwrap();
+                if token.strong_count() == 0 {
+                    return;
+                }
+                last = Some(instances.clone());
+                inner.broadcaster.send(Discovery {
+                    key: key.clone(),
+                    instance_cluster: InstanceCluster::Rpc(instances),
+                });
+            },
+            Err(e) => {
+                warn!("[LOGIMESH] failed to watch consul: {e}");
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{ConsulRegistrar, Registrar};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::Notify;
+
+    /// A Consul agent stub that serves the registered services as passing instances.
+    #[derive(Default)]
+    struct Stub {
+        state: Mutex<(u64, HashMap<String, serde_json::Value>)>,
+        changed: Notify,
+    }
+
+    impl Stub {
+        async fn handle(&self, request: &str) -> (u64, String) {
+            let (head, body) = request.split_once("\r\n\r\n").unwrap();
+            let mut parts = head.split(' ');
+            let (method, target) = (parts.next().unwrap(), parts.next().unwrap());
+            let (path, query) = target.split_once('?').unwrap_or((target, ""));
+            match (method, path) {
+                ("PUT", "/v1/agent/service/register") => {
+                    let service: serde_json::Value = serde_json::from_str(body).unwrap();
+                    self.update(|services| services.insert(service["ID"].as_str().unwrap().to_string(), service));
+                },
+                ("PUT", path) if path.starts_with("/v1/agent/service/deregister/") => {
+                    self.update(|services| services.remove(&path.trim_start_matches("/v1/agent/service/deregister/").replace("%3A", ":")));
+                },
+                ("GET", path) if path.starts_with("/v1/health/service/") => {
+                    let name = path.trim_start_matches("/v1/health/service/");
+                    let tags = query.split('&').filter_map(|kv| kv.strip_prefix("tag=")).collect::<Vec<_>>();
+                    let index = query.split('&').find_map(|kv| kv.strip_prefix("index=")).map_or(0, |v| v.parse().unwrap());
+                    if index > 0 {
+                        let changed = self.changed.notified();
+                        if self.state.lock().unwrap().0 == index {
+                            let _ = tokio::time::timeout(Duration::from_secs(1), changed).await;
+                        }
+                    }
+                    let state = self.state.lock().unwrap();
+                    let entries = state
+                        .1
+                        .values()
+                        .filter(|service| service["Name"] == name && tags.iter().all(|tag| service["Tags"].as_array().unwrap().iter().any(|t| t == tag)))
+                        .map(|service| serde_json::json!({ "Node": { "Address": "127.0.0.1" }, "Service": service }))
+                        .collect::<Vec<_>>();
+                    return (state.0, serde_json::to_string(&entries).unwrap());
+                },
+                _ => panic!("unexpected request: {head}"),
+            }
+            (self.state.lock().unwrap().0, String::new())
+        }
+
+        fn update<R>(&self, f: impl FnOnce(&mut HashMap<String, serde_json::Value>) -> R) {
+            let mut state = self.state.lock().unwrap();
+            f(&mut state.1);
+            state.0 += 1;
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// Serves a Consul agent stub, and returns its address.
+    async fn serve_stub() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let stub = Arc::new(Stub {
+            state: Mutex::new((1, HashMap::new())),
+            changed: Notify::new(),
+        });
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let stub = stub.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0; 64 * 1024];
+                    let mut len = 0;
+                    // Read the head and the body according to the Content-Length.
+                    let request = loop {
+                        len += stream.read(&mut buf[len..]).await.unwrap();
+                        let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                        if let Some((head, body)) = request.split_once("\r\n\r\n") {
+                            let content_length = head.lines().find_map(|line| line.strip_prefix("Content-Length: ")).map_or(0, |v| v.parse().unwrap());
+                            if body.len() >= content_length {
+                                break request;
+                            }
+                        }
+                    };
+                    let (index, body) = stub.handle(&request).await;
+                    let response = format!("HTTP/1.1 200 OK\r\nX-Consul-Index: {index}\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn discover_and_watch() {
+        let agent = serve_stub().await;
+        let discover = ConsulDiscover::new(agent.clone()).with_wait(Duration::from_secs(1));
+        let mut receiver = discover.watch(None).unwrap();
+        let mut endpoint = Endpoint::new("hello");
+        endpoint.insert::<ConsulTags>("primary".into());
+        assert_eq!(discover.discover(&endpoint).await.unwrap().instance_cluster, InstanceCluster::Rpc(vec![]));
+
+        let registrar = ConsulRegistrar::new(agent).with_weight(3);
+        let _secondary = registrar.register(&Endpoint::new("hello"), &"127.0.0.1:8887".parse().unwrap()).await.unwrap();
+        let primary = registrar.register(&endpoint, &"127.0.0.1:8888".parse().unwrap()).await.unwrap();
+        let discovery = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(discovery.key, "hello");
+        assert_eq!(
+            discovery.instance_cluster,
+            InstanceCluster::Rpc(vec![Arc::new(Instance {
+                address: "127.0.0.1:8888".parse().unwrap(),
+                weight: 3,
+                tags: [("primary".into(), "".into())].into_iter().collect(),
+            })])
+        );
+
+        registrar.deregister(primary).await.unwrap();
+        let discovery = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(discovery.instance_cluster, InstanceCluster::Rpc(vec![]));
+
+        // The same key with other tags replaces the watcher of the key.
+        discover.discover(&Endpoint::new("hello")).await.unwrap();
+        discover.discover(&endpoint).await.unwrap();
+        assert_eq!(discover.inner.watching.lock().unwrap().len(), 1);
+        let _primary = registrar.register(&endpoint, &"127.0.0.1:8888".parse().unwrap()).await.unwrap();
+        let discovery = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(discovery.instance_cluster.instances().unwrap().len(), 1);
+        // The replaced watcher does not report the instances without the tags.
+        assert!(tokio::time::timeout(Duration::from_millis(300), receiver.recv()).await.is_err());
+    }
+}
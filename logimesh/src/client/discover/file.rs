@@ -0,0 +1,325 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! File-backed discover with hot reload.
+
+use super::{Discover, Discovery, DiscoveryBroadcaster, Instance, InstanceCluster};
+use crate::client::ClientError;
+use crate::component::Endpoint;
+use crate::net::address::Address;
+use async_broadcast::Receiver;
+use faststr::FastStr;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tracing::{trace, warn};
+
+/// Default interval between two checks of the file.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// [`FileDiscover`] reads the endpoint key → instance list mapping from a TOML or JSON file,
+/// and pushes a fresh [`Discovery`] through [`Discover::watch`] every time the file changes.
+///
+/// The file format is chosen by its extension (`.toml` or `.json`). Each top-level entry is an
+/// endpoint key, and keys that are absent from the file resolve to [`InstanceCluster::Lpc`].
+///
+/// ```toml
+/// [hello]
+/// instances = [
+///     { address = "127.0.0.1:8888", weight = 10, tags = { zone = "us-east-1a" } },
+///     { address = "127.0.0.1:8889" },
+/// ]
+///
+/// [local_only]
+/// lpc = true
+///
+/// [carved_out]
+/// rpc_percent = 10
+/// instances = [{ address = "127.0.0.1:8890" }]
+/// ```
+///
+/// `rpc_percent` is the percentage of the calls that are remote calls, see [`InstanceCluster::Split`], default is 100.
+#[derive(Clone)]
+pub struct FileDiscover {
+    inner: Arc<Inner>,
+    poll_interval: Duration,
+}
+
+struct Inner {
+    path: PathBuf,
+    format: FileFormat,
+    state: RwLock<FileState>,
+    broadcaster: DiscoveryBroadcaster,
+    watching: AtomicBool,
+}
+
+#[derive(Default)]
+struct FileState {
+    modified: Option<SystemTime>,
+    clusters: HashMap<FastStr, InstanceCluster>,
+}
+
+#[derive(Clone, Copy)]
+enum FileFormat {
+    Toml,
+    Json,
+}
+
+#[derive(Deserialize)]
+struct FileEntry {
+    #[serde(default)]
+    lpc: bool,
+    #[serde(default = "default_rpc_percent")]
+    rpc_percent: u8,
+    #[serde(default)]
+    instances: Vec<FileInstance>,
+}
+
+#[derive(Deserialize)]
+struct FileInstance {
+    address: String,
+    #[serde(default = "default_weight")]
+    weight: u32,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_rpc_percent() -> u8 {
+    100
+}
+
+impl FileDiscover {
+    /// Creates a new [`FileDiscover`] and loads the file for the first time.
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, ClientError> {
+        let path = path.as_ref().to_path_buf();
+        let format = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => FileFormat::Toml,
+            Some("json") => FileFormat::Json,
+            _ => return Err(ClientError::Discover(format!("unsupported discover file {}, expect a .toml or .json file", path.display()).into())),
+        };
+        let modified = modified_time(&path);
+        let clusters = load(&path, format)?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                path,
+                format,
+                state: RwLock::new(FileState { modified, clusters }),
+                broadcaster: DiscoveryBroadcaster::new(),
+                watching: AtomicBool::new(false),
+            }),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        })
+    }
+
+    /// Set the interval between two checks of the file, default is 1s.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Reloads the file immediately and pushes the changed keys through [`Discover::watch`].
+    pub fn reload(&self) -> Result<(), ClientError> {
+        self.inner.reload()
+    }
+}
+
+impl Inner {
+    fn cluster(&self, key: &FastStr) -> InstanceCluster {
+        self.state.read().unwrap().clusters.get(key).cloned().unwrap_or(InstanceCluster::Lpc)
+    }
+
+    fn is_modified(&self) -> bool {
+        let modified = modified_time(&self.path);
+        modified.is_some() && self.state.read().unwrap().modified != modified
+    }
+
+    fn reload(&self) -> Result<(), ClientError> {
+        let modified = modified_time(&self.path);
+        let next = match load(&self.path, self.format) {
+            Ok(next) => next,
+            Err(e) => {
+                // Keep serving the last good content, and don't retry until the file changes again.
+                self.state.write().unwrap().modified = modified;
+                return Err(e);
+            },
+        };
+        let mut changes = Vec::new();
+        {
+            let mut state = self.state.write().unwrap();
+            for (key, cluster) in &next {
+                if state.clusters.get(key) != Some(cluster) {
+                    changes.push(Discovery {
+                        key: key.clone(),
+                        instance_cluster: cluster.clone(),
+                    });
+                }
+            }
+            for key in state.clusters.keys() {
+                if !next.contains_key(key) {
+                    changes.push(Discovery {
+                        key: key.clone(),
+                        instance_cluster: InstanceCluster::Lpc,
+                    });
+                }
+            }
+            state.modified = modified;
+            state.clusters = next;
+        }
+        for discovery in changes {
+            trace!("[LOGIMESH] discover file changed: {discovery:?}");
+            self.broadcaster.send(discovery);
+        }
+        Ok(())
+    }
+}
+
+impl Discover for FileDiscover {
+    fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send {
+        async move {
+            let key = endpoint.key();
+            Ok(Discovery {
+                instance_cluster: self.inner.cluster(&key),
+                key,
+            })
+        }
+    }
+
+    fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+        let receiver = self.inner.broadcaster.subscribe(keys);
+        if !self.inner.watching.swap(true, Ordering::AcqRel) {
+            let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+                self.inner.watching.store(false, Ordering::Release);
+                warn!("[LOGIMESH] discover file can not be watched outside of a tokio runtime");
+                return None;
+            };
+            runtime.spawn(poll(Arc::downgrade(&self.inner), self.poll_interval));
+        }
+        Some(receiver)
+    }
+}
+
+async fn poll(inner: Weak<Inner>, poll_interval: Duration) {
+    let mut interval = tokio::time::interval(poll_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(inner) = inner.upgrade() else {
+            return;
+        };
+        if inner.is_modified() {
+            if let Err(e) = inner.reload() {
+                warn!("[LOGIMESH] failed to reload discover file: {e:?}");
+            }
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load(path: &Path, format: FileFormat) -> Result<HashMap<FastStr, InstanceCluster>, ClientError> {
+    let content = std::fs::read_to_string(path).map_err(|e| ClientError::Discover(format!("failed to read {}: {e}", path.display()).into()))?;
+    parse(&content, format).map_err(|e| ClientError::Discover(format!("failed to parse {}: {e}", path.display()).into()))
+}
+
+fn parse(content: &str, format: FileFormat) -> Result<HashMap<FastStr, InstanceCluster>, anyhow::Error> {
+    let entries: HashMap<String, FileEntry> = match format {
+        FileFormat::Toml => toml::from_str(content)?,
+        FileFormat::Json => serde_json::from_str(content)?,
+    };
+    let mut clusters = HashMap::with_capacity(entries.len());
+    for (key, entry) in entries {
+        let cluster = if entry.lpc {
+            InstanceCluster::Lpc
+        } else {
+            let mut instances = Vec::with_capacity(entry.instances.len());
+            for instance in entry.instances {
+                let address: Address = instance.address.parse().map_err(|e| anyhow::anyhow!("invalid address {}: {e}", instance.address))?;
+                instances.push(Arc::new(Instance {
+                    address,
+                    weight: instance.weight,
+                    tags: instance.tags.into_iter().map(|(k, v)| (k.into(), v.into())).collect(),
+                }));
+            }
+            InstanceCluster::split(entry.rpc_percent, instances)
+        };
+        clusters.insert(FastStr::from(key), cluster);
+    }
+    Ok(clusters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_toml_and_json() {
+        let toml = r#"
+            [hello]
+            instances = [
+                { address = "127.0.0.1:8888", weight = 10, tags = { zone = "us-east-1a" } },
+                { address = "127.0.0.1:8889" },
+            ]
+
+            [local_only]
+            lpc = true
+
+            [carved_out]
+            rpc_percent = 10
+            instances = [{ address = "127.0.0.1:8890" }]
+        "#;
+        let json = r#"{
+            "hello": {"instances": [
+                {"address": "127.0.0.1:8888", "weight": 10, "tags": {"zone": "us-east-1a"}},
+                {"address": "127.0.0.1:8889"}
+            ]},
+            "local_only": {"lpc": true},
+            "carved_out": {"rpc_percent": 10, "instances": [{"address": "127.0.0.1:8890"}]}
+        }"#;
+        let from_toml = parse(toml, FileFormat::Toml).unwrap();
+        let from_json = parse(json, FileFormat::Json).unwrap();
+        assert_eq!(from_toml, from_json);
+        assert_eq!(from_toml.get("local_only"), Some(&InstanceCluster::Lpc));
+        assert_eq!(from_toml.get("carved_out").unwrap().rpc_percent(), 10);
+        let InstanceCluster::Rpc(instances) = from_toml.get("hello").unwrap() else {
+            panic!("expect rpc instances");
+        };
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].weight, 10);
+        assert_eq!(instances[0].tags.get("zone").map(|v| v.as_ref()), Some("us-east-1a"));
+        assert_eq!(instances[1].weight, 1);
+    }
+
+    #[tokio::test]
+    async fn watch_file_changes() {
+        let path = std::env::temp_dir().join(format!("logimesh_file_discover_{:016x}.json", rand::random::<u64>()));
+        std::fs::write(&path, r#"{"hello": {"instances": [{"address": "127.0.0.1:8888"}]}}"#).unwrap();
+        let discover = FileDiscover::new(&path).unwrap().with_poll_interval(Duration::from_millis(10));
+        let mut receiver = discover.watch(None).unwrap();
+
+        // Make sure the modification time changes on file systems with a coarse resolution.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        std::fs::write(&path, r#"{"hello": {"lpc": true}}"#).unwrap();
+        let discovery = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(discovery.key, "hello");
+        assert_eq!(discovery.instance_cluster, InstanceCluster::Lpc);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
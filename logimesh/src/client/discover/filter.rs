@@ -0,0 +1,328 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! Tag-based instance filtering.
+
+use super::{Discover, Discovery, DiscoveryBroadcaster, Instance, InstanceCluster};
+use crate::client::ClientError;
+use crate::component::Endpoint;
+use async_broadcast::Receiver;
+use faststr::FastStr;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// [`Endpoint`] tag of the [`Selector`] that the [`Instance::tags`] must satisfy, see [`FilterDiscover`].
+///
+/// # Example:
+/// ```
+/// use logimesh::client::discover::TagSelector;
+/// use logimesh::component::Endpoint;
+///
+/// let mut endpoint = Endpoint::new("hello");
+/// endpoint.insert::<TagSelector>("zone=us-east-1a, version in (v2, v3)".into());
+/// ```
+pub struct TagSelector;
+
+/// A list of requirements on the [`Instance::tags`], separated by commas. All of them must be satisfied.
+///
+/// | Requirement          | Satisfied when                          |
+/// |----------------------|-----------------------------------------|
+/// | `key=value`          | the tag equals the value                |
+/// | `key!=value`         | the tag is missing or differs           |
+/// | `key in (v1, v2)`    | the tag equals one of the values        |
+/// | `key notin (v1, v2)` | the tag is missing or equals none       |
+/// | `key`                | the tag exists                          |
+/// | `!key`               | the tag is missing                      |
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Selector {
+    requirements: Vec<Requirement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Requirement {
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    NotExists(String),
+}
+
+/// An error which can be returned when parsing a [`Selector`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid tag selector requirement `{0}`")]
+pub struct SelectorParseError(String);
+
+impl Selector {
+    /// Returns whether the instance satisfies all requirements.
+    pub fn matches(&self, instance: &Instance) -> bool {
+        self.requirements.iter().all(|requirement| {
+            let tag = |key: &str| instance.tags.get(key).map(|value| value.as_ref());
+            match requirement {
+                Requirement::In(key, values) => tag(key).is_some_and(|value| values.iter().any(|v| v == value)),
+                Requirement::NotIn(key, values) => tag(key).map_or(true, |value| values.iter().all(|v| v != value)),
+                Requirement::Exists(key) => tag(key).is_some(),
+                Requirement::NotExists(key) => tag(key).is_none(),
+            }
+        })
+    }
+
+    /// Returns whether there is no requirement.
+    pub fn is_empty(&self) -> bool {
+        self.requirements.is_empty()
+    }
+
+    /// Keeps the instances that satisfy all requirements, local calls are not filtered.
+    pub fn filter(&self, instance_cluster: InstanceCluster) -> InstanceCluster {
+        if self.is_empty() {
+            return instance_cluster;
+        }
+        instance_cluster.map_instances(|instances| instances.into_iter().filter(|instance| self.matches(instance)).collect())
+    }
+}
+
+impl FromStr for Selector {
+    type Err = SelectorParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Split on the commas outside of parentheses.
+        let mut parts = Vec::new();
+        let (mut depth, mut start) = (0, 0);
+        for (i, c) in s.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    parts.push(&s[start..i]);
+                    start = i + 1;
+                },
+                _ => {},
+            }
+        }
+        parts.push(&s[start..]);
+        let requirements = parts
+            .into_iter()
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .map(|part| parse_requirement(part).ok_or_else(|| SelectorParseError(part.to_string())))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { requirements })
+    }
+}
+
+fn parse_requirement(s: &str) -> Option<Requirement> {
+    let is_key = |key: &str| !key.is_empty() && !key.contains(|c: char| c.is_whitespace() || "=!(),".contains(c));
+    if let Some((key, value)) = s.split_once("!=") {
+        let (key, value) = (key.trim(), value.trim());
+        return (is_key(key) && is_key(value)).then(|| Requirement::NotIn(key.to_string(), vec![value.to_string()]));
+    }
+    if let Some((key, value)) = s.split_once('=') {
+        let (key, value) = (key.trim(), value.trim_start_matches('=').trim());
+        return (is_key(key) && is_key(value)).then(|| Requirement::In(key.to_string(), vec![value.to_string()]));
+    }
+    if let Some(key) = s.strip_prefix('!') {
+        let key = key.trim();
+        return is_key(key).then(|| Requirement::NotExists(key.to_string()));
+    }
+    let mut words = s.splitn(2, char::is_whitespace);
+    let key = words.next()?;
+    if !is_key(key) {
+        return None;
+    }
+    let Some(rest) = words.next().map(str::trim) else {
+        return Some(Requirement::Exists(key.to_string()));
+    };
+    let (op, values) = rest.split_once('(')?;
+    let values = values.trim_end().strip_suffix(')')?.split(',').map(str::trim).map(String::from).collect::<Vec<_>>();
+    if values.iter().any(|value| !is_key(value)) {
+        return None;
+    }
+    match op.trim() {
+        "in" => Some(Requirement::In(key.to_string(), values)),
+        "notin" => Some(Requirement::NotIn(key.to_string(), values)),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, requirement) in self.requirements.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            match requirement {
+                Requirement::In(key, values) if values.len() == 1 => write!(f, "{key}={}", values[0])?,
+                Requirement::NotIn(key, values) if values.len() == 1 => write!(f, "{key}!={}", values[0])?,
+                Requirement::In(key, values) => write!(f, "{key} in ({})", values.join(", "))?,
+                Requirement::NotIn(key, values) => write!(f, "{key} notin ({})", values.join(", "))?,
+                Requirement::Exists(key) => write!(f, "{key}")?,
+                Requirement::NotExists(key) => write!(f, "!{key}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// [`FilterDiscover`] keeps only the instances whose tags satisfy the [`TagSelector`] of the endpoint,
+/// and filters every change reported by the inner [`Discover::watch`] again.
+///
+/// Endpoints without a [`TagSelector`] get all instances.
+///
+/// The changes are pushed per endpoint key, so the endpoints sharing a key must share the selector too.
+/// An endpoint with another selector is rejected, and should have a distinct key, see [`Endpoint::key_maker`].
+pub struct FilterDiscover<D> {
+    inner: Arc<Inner<D>>,
+}
+
+struct Inner<D> {
+    discover: D,
+    selectors: Mutex<HashMap<FastStr, (Selector, InstanceCluster)>>,
+    broadcaster: DiscoveryBroadcaster,
+    watching: AtomicBool,
+}
+
+impl<D> Clone for FilterDiscover<D> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<D: Discover> FilterDiscover<D> {
+    /// Creates a new [`FilterDiscover`] wrapping the discover.
+    pub fn new(discover: D) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                discover,
+                selectors: Mutex::new(HashMap::new()),
+                broadcaster: DiscoveryBroadcaster::new(),
+                watching: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Subscribes the inner discover, and filters its changes in the background.
+    fn start_watching(&self) {
+        if self.inner.watching.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            self.inner.watching.store(false, Ordering::Release);
+            return;
+        };
+        if let Some(mut receiver) = self.inner.discover.watch(None) {
+            let weak = Arc::downgrade(&self.inner);
+            runtime.spawn(async move {
+                while let Some(discovery) = receiver.next().await {
+                    let Some(inner) = weak.upgrade() else {
+                        return;
+                    };
+                    inner.update(discovery);
+                }
+            });
+        }
+    }
+}
+
+impl<D> Inner<D> {
+    fn update(&self, discovery: Discovery) {
+        let discovery = {
+            let mut selectors = self.selectors.lock().unwrap();
+            let Some((selector, last)) = selectors.get_mut(&discovery.key) else {
+                return;
+            };
+            let instance_cluster = selector.filter(discovery.instance_cluster);
+            if *last == instance_cluster {
+                return;
+            }
+            *last = instance_cluster.clone();
+            Discovery { key: discovery.key, instance_cluster }
+        };
+        self.broadcaster.send(discovery);
+    }
+}
+
+impl<D: Discover> Discover for FilterDiscover<D> {
+    fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send {
+        async move {
+            let selector: Selector = match endpoint.get::<TagSelector>() {
+                Some(selector) => selector.parse().map_err(|e| ClientError::Discover(format!("{e}").into()))?,
+                None => Selector::default(),
+            };
+            let key = endpoint.key();
+            if self.inner.selectors.lock().unwrap().get(&key).is_some_and(|(other, _)| *other != selector) {
+                return Err(ClientError::Discover(format!("the endpoint key {key} is discovered with another tag selector").into()));
+            }
+            self.start_watching();
+            let discovery = self.inner.discover.discover(endpoint).await?;
+            let instance_cluster = selector.filter(discovery.instance_cluster);
+            self.inner.selectors.lock().unwrap().insert(discovery.key.clone(), (selector, instance_cluster.clone()));
+            Ok(Discovery { key: discovery.key, instance_cluster })
+        }
+    }
+
+    fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+        self.start_watching();
+        Some(self.inner.broadcaster.subscribe(keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::discover::FixedDiscover;
+    use std::time::Duration;
+
+    fn instance(address: &str, tags: &[(&'static str, &'static str)]) -> Arc<Instance> {
+        Arc::new(Instance {
+            address: address.parse().unwrap(),
+            weight: 1,
+            tags: tags.iter().map(|(k, v)| ((*k).into(), (*v).into())).collect(),
+        })
+    }
+
+    #[test]
+    fn parse_selector() {
+        let selector: Selector = "zone=us-east-1a, version in (v2, v3), env != test, canary notin (yes), !draining, shard".parse().unwrap();
+        assert_eq!(selector.to_string(), "zone=us-east-1a, version in (v2, v3), env!=test, canary!=yes, !draining, shard");
+        assert!(selector.matches(&instance("127.0.0.1:8000", &[("zone", "us-east-1a"), ("version", "v3"), ("shard", "1")])));
+        assert!(!selector.matches(&instance("127.0.0.1:8000", &[("zone", "us-east-1a"), ("version", "v1"), ("shard", "1")])));
+        assert!(!selector.matches(&instance("127.0.0.1:8000", &[("zone", "us-east-1a"), ("version", "v2"), ("shard", "1"), ("draining", "")])));
+        assert!("zone in (a".parse::<Selector>().is_err());
+        assert!("zone ~ a".parse::<Selector>().is_err());
+    }
+
+    #[tokio::test]
+    async fn filter_watch_updates() {
+        let fixed = FixedDiscover::new(InstanceCluster::Rpc(vec![instance("127.0.0.1:8000", &[("zone", "a")]), instance("127.0.0.1:8001", &[("zone", "b")])]));
+        let discover = FilterDiscover::new(fixed.clone());
+        let mut receiver = discover.watch(None).unwrap();
+        let mut endpoint = Endpoint::new("hello");
+        endpoint.insert::<TagSelector>("zone=a".into());
+        assert_eq!(
+            discover.discover(&endpoint).await.unwrap().instance_cluster,
+            InstanceCluster::Rpc(vec![instance("127.0.0.1:8000", &[("zone", "a")])])
+        );
+
+        // A change of the filtered out instances is not reported.
+        fixed.handle().patch_key("hello", vec![instance("127.0.0.1:8002", &[("zone", "b")])], &[]);
+        fixed.handle().patch_key("hello", vec![instance("127.0.0.1:8003", &[("zone", "a")])], &[]);
+        let discovery = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(
+            discovery.instance_cluster,
+            InstanceCluster::Rpc(vec![instance("127.0.0.1:8000", &[("zone", "a")]), instance("127.0.0.1:8003", &[("zone", "a")])])
+        );
+
+        // The endpoints sharing the key must share the selector.
+        let mut other = Endpoint::new("hello");
+        other.insert::<TagSelector>("zone=b".into());
+        assert!(discover.discover(&other).await.is_err());
+        other.insert::<TagSelector>("zone = a".into());
+        assert!(discover.discover(&other).await.is_ok());
+    }
+}
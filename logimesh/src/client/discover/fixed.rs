@@ -7,26 +7,92 @@
 //!
 //! Fixed instance list discover.
 
-use super::{Discover, Discovery, Instance, InstanceCluster};
+use super::{Discover, Discovery, DiscoveryBroadcaster, Instance, InstanceCluster};
 use crate::client::ClientError;
 use crate::component::Endpoint;
-use crate::net::address::Address;
+use crate::net::address::{Address, AddressParseError};
 use async_broadcast::Receiver;
 use faststr::FastStr;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
-use std::net::AddrParseError;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 /// [`FixedDiscover`] is a simple implementation of [`Discover`] that returns a fixed list of instances.
+///
+/// The list can still be changed at runtime through a [`FixedDiscoverHandle`],
+/// and every change is pushed to the receivers returned by [`Discover::watch`].
 #[derive(Clone)]
 pub struct FixedDiscover {
-    instance_cluster: InstanceCluster,
+    inner: Arc<Inner>,
+}
+
+/// [`FixedDiscoverHandle`] updates the instances of a running [`FixedDiscover`].
+///
+/// Changes of the default instance cluster apply to every endpoint key that has no instance cluster of its own.
+#[derive(Clone)]
+pub struct FixedDiscoverHandle {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    state: RwLock<FixedState>,
+    broadcaster: DiscoveryBroadcaster,
+}
+
+struct FixedState {
+    default: KeyState,
+    keys: HashMap<FastStr, KeyState>,
+    discovered: HashSet<FastStr>,
+}
+
+#[derive(Clone)]
+struct KeyState {
+    lpc: bool,
+    rpc_percent: u8,
+    instances: Vec<Arc<Instance>>,
+}
+
+impl From<InstanceCluster> for KeyState {
+    fn from(instance_cluster: InstanceCluster) -> Self {
+        match instance_cluster {
+            InstanceCluster::Lpc => Self {
+                lpc: true,
+                rpc_percent: 100,
+                instances: vec![],
+            },
+            InstanceCluster::Rpc(instances) => Self {
+                lpc: false,
+                rpc_percent: 100,
+                instances,
+            },
+            InstanceCluster::Split { rpc_percent, instances } => Self {
+                lpc: false,
+                rpc_percent,
+                instances,
+            },
+        }
+    }
+}
+
+impl KeyState {
+    fn instance_cluster(&self) -> InstanceCluster {
+        if self.lpc { InstanceCluster::Lpc } else { InstanceCluster::split(self.rpc_percent, self.instances.clone()) }
+    }
 }
 
 impl FixedDiscover {
     /// Creates a new [`FixedDiscover`].
     pub fn new(instance_cluster: InstanceCluster) -> Self {
-        Self { instance_cluster }
+        Self {
+            inner: Arc::new(Inner {
+                state: RwLock::new(FixedState {
+                    default: instance_cluster.into(),
+                    keys: HashMap::new(),
+                    discovered: HashSet::new(),
+                }),
+                broadcaster: DiscoveryBroadcaster::new(),
+            }),
+        }
     }
     /// Creates a new [`FixedDiscover`] from address.
     pub fn from_address(address_list: Vec<Address>) -> Self {
@@ -40,31 +106,118 @@ impl FixedDiscover {
                 })
             })
             .collect();
-        Self {
-            instance_cluster: InstanceCluster::Rpc(instances),
-        }
+        Self::new(InstanceCluster::Rpc(instances))
     }
     /// Creates a new [`FixedDiscover`] from address.
-    pub fn from_address_str(address_list: Vec<impl AsRef<str>>) -> Result<Self, AddrParseError> {
+    pub fn from_address_str(address_list: Vec<impl AsRef<str>>) -> Result<Self, AddressParseError> {
         let mut list = Vec::new();
         for ele in address_list {
             list.push(ele.as_ref().parse()?);
         }
         Ok(Self::from_address(list))
     }
+    /// Returns a handle to update the instances at runtime.
+    pub fn handle(&self) -> FixedDiscoverHandle {
+        FixedDiscoverHandle { inner: self.inner.clone() }
+    }
+}
+
+impl FixedDiscoverHandle {
+    /// Returns the current instance cluster of the endpoint key.
+    pub fn instance_cluster(&self, key: &str) -> InstanceCluster {
+        let state = self.inner.state.read().unwrap();
+        state.keys.get(key).unwrap_or(&state.default).instance_cluster()
+    }
+
+    /// Replaces the default instance cluster.
+    pub fn replace(&self, instance_cluster: InstanceCluster) {
+        let changes = {
+            let mut state = self.inner.state.write().unwrap();
+            let next: KeyState = instance_cluster.into();
+            if state.default.instance_cluster() == next.instance_cluster() {
+                state.default = next;
+                return;
+            }
+            state.default = next;
+            let state = &*state;
+            state
+                .discovered
+                .iter()
+                .filter(|key| !state.keys.contains_key(*key))
+                .map(|key| Discovery {
+                    key: key.clone(),
+                    instance_cluster: state.default.instance_cluster(),
+                })
+                .collect::<Vec<_>>()
+        };
+        for discovery in changes {
+            self.inner.broadcaster.send(discovery);
+        }
+    }
+
+    /// Replaces the instance cluster of the endpoint key.
+    pub fn replace_key(&self, key: impl Into<FastStr>, instance_cluster: InstanceCluster) {
+        self.update(key.into(), |key_state| *key_state = instance_cluster.into());
+    }
+
+    /// Adds or updates instances of the endpoint key, and removes the instances with the given addresses.
+    ///
+    /// Instances are matched by address, so an added instance replaces the existing one with the same address.
+    pub fn patch_key(&self, key: impl Into<FastStr>, added: Vec<Arc<Instance>>, removed: &[Address]) {
+        self.update(key.into(), |key_state| {
+            key_state.instances.retain(|instance| !removed.contains(&instance.address));
+            for instance in added {
+                match key_state.instances.iter_mut().find(|old| old.address == instance.address) {
+                    Some(old) => *old = instance,
+                    None => key_state.instances.push(instance),
+                }
+            }
+        });
+    }
+
+    /// Switches the endpoint key between [`InstanceCluster::Lpc`] and [`InstanceCluster::Rpc`].
+    ///
+    /// The instance list is kept while the key uses local calls, so switching back restores it.
+    pub fn set_lpc(&self, key: impl Into<FastStr>, lpc: bool) {
+        self.update(key.into(), |key_state| key_state.lpc = lpc);
+    }
+
+    /// Sets the percentage of the calls of the endpoint key that are remote calls, see [`InstanceCluster::Split`].
+    ///
+    /// It applies while the key does not use local calls only, and 100 switches back to [`InstanceCluster::Rpc`].
+    pub fn set_rpc_percent(&self, key: impl Into<FastStr>, rpc_percent: u8) {
+        self.update(key.into(), |key_state| key_state.rpc_percent = rpc_percent.min(100));
+    }
+
+    fn update(&self, key: FastStr, f: impl FnOnce(&mut KeyState)) {
+        let discovery = {
+            let mut state = self.inner.state.write().unwrap();
+            let default = state.default.clone();
+            let key_state = state.keys.entry(key.clone()).or_insert(default);
+            let prev = key_state.instance_cluster();
+            f(key_state);
+            let instance_cluster = key_state.instance_cluster();
+            if instance_cluster == prev {
+                return;
+            }
+            Discovery { key, instance_cluster }
+        };
+        self.inner.broadcaster.send(discovery);
+    }
 }
 
 impl Discover for FixedDiscover {
     fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send {
         async move {
-            Ok(Discovery {
-                key: endpoint.key(),
-                instance_cluster: self.instance_cluster.clone(),
-            })
+            let key = endpoint.key();
+            let mut state = self.inner.state.write().unwrap();
+            let instance_cluster = state.keys.get(&key).unwrap_or(&state.default).instance_cluster();
+            state.discovered.insert(key.clone());
+            Ok(Discovery { key, instance_cluster })
         }
     }
 
-    fn watch(&self, _keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
-        None
+    fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+        Some(self.inner.broadcaster.subscribe(keys))
     }
 }
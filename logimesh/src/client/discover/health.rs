@@ -0,0 +1,386 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! Active health-checking discover layer.
+
+use super::{Discover, Discovery, DiscoveryBroadcaster, Instance, InstanceCluster};
+use crate::client::ClientError;
+use crate::component::Endpoint;
+use crate::health::{ServingStatus, HEALTH_ADDRESS_TAG};
+#[cfg(target_family = "unix")]
+use crate::net::address::connect_unix;
+use crate::net::Address;
+use async_broadcast::Receiver;
+use faststr::FastStr;
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Default interval between two rounds of checks.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+/// Default time a check may take before it fails.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Default number of consecutive failed checks that removes an instance.
+const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+
+/// [`HealthCheck`] probes whether an instance is healthy.
+pub trait HealthCheck: Send + Sync + 'static {
+    /// Returns whether the instance is healthy.
+    fn check<'s>(&'s self, instance: &'s Instance) -> impl Future<Output = bool> + Send;
+}
+
+/// [`TcpConnectCheck`] considers an instance healthy when a connection to it can be established,
+/// over TCP or over the unix socket of the instance.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpConnectCheck;
+
+impl HealthCheck for TcpConnectCheck {
+    fn check<'s>(&'s self, instance: &'s Instance) -> impl Future<Output = bool> + Send {
+        async move {
+            match &instance.address {
+                Address::Ip(address) => tokio::net::TcpStream::connect(address).await.is_ok(),
+                #[cfg(target_family = "unix")]
+                Address::Unix(address) => connect_unix(address).await.is_ok(),
+            }
+        }
+    }
+}
+
+/// [`RpcHealthCheck`] asks the built-in [`crate::health::Health`] component of the instance whether it is serving.
+///
+/// The component is reached at the [`HEALTH_ADDRESS_TAG`] of the instance, or at the instance address if the tag is missing.
+#[derive(Clone, Debug, Default)]
+pub struct RpcHealthCheck {
+    service: String,
+}
+
+impl RpcHealthCheck {
+    /// Creates a new [`RpcHealthCheck`] that asks for the whole server.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the service name to ask for.
+    pub fn with_service(mut self, service: impl Into<String>) -> Self {
+        self.service = service.into();
+        self
+    }
+}
+
+impl HealthCheck for RpcHealthCheck {
+    fn check<'s>(&'s self, instance: &'s Instance) -> impl Future<Output = bool> + Send {
+        async move {
+            let address = match instance.tags.get(HEALTH_ADDRESS_TAG) {
+                Some(address) => match address.parse::<Address>() {
+                    Ok(address) => address,
+                    Err(_) => return false,
+                },
+                None => instance.address.clone(),
+            };
+            let Ok(client) = crate::health::connect_address(&address).await else {
+                return false;
+            };
+            matches!(client.check(crate::context::current(), self.service.clone()).await, Ok(ServingStatus::Serving))
+        }
+    }
+}
+
+/// [`HealthCheckDiscover`] actively probes every discovered instance of the inner discover in the background.
+///
+/// An instance that fails a number of consecutive checks is removed from the emitted [`InstanceCluster::Rpc`],
+/// and comes back as soon as a check succeeds again. New instances are healthy until they fail.
+/// The changes are pushed through [`Discover::watch`].
+pub struct HealthCheckDiscover<D, C = TcpConnectCheck> {
+    inner: Arc<Inner<D, C>>,
+}
+
+struct Inner<D, C> {
+    discover: D,
+    check: C,
+    state: Mutex<HealthState>,
+    broadcaster: DiscoveryBroadcaster,
+    watching: AtomicBool,
+}
+
+struct HealthState {
+    interval: Duration,
+    timeout: Duration,
+    failure_threshold: u32,
+    /// The unfiltered and the emitted instance clusters of the discovered keys.
+    keys: HashMap<FastStr, (InstanceCluster, InstanceCluster)>,
+    /// Consecutive failed checks of the instances.
+    failures: HashMap<Address, u32>,
+}
+
+impl<D, C> Clone for HealthCheckDiscover<D, C> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<D: Discover> HealthCheckDiscover<D> {
+    /// Creates a new [`HealthCheckDiscover`] probing with [`TcpConnectCheck`].
+    pub fn new(discover: D) -> Self {
+        Self::with_check(discover, TcpConnectCheck)
+    }
+}
+
+impl<D: Discover, C: HealthCheck> HealthCheckDiscover<D, C> {
+    /// Creates a new [`HealthCheckDiscover`] probing with the health check.
+    pub fn with_check(discover: D, check: C) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                discover,
+                check,
+                state: Mutex::new(HealthState {
+                    interval: DEFAULT_INTERVAL,
+                    timeout: DEFAULT_TIMEOUT,
+                    failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+                    keys: HashMap::new(),
+                    failures: HashMap::new(),
+                }),
+                broadcaster: DiscoveryBroadcaster::new(),
+                watching: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Set the interval between two rounds of checks, default is 5s.
+    pub fn with_interval(self, interval: Duration) -> Self {
+        self.inner.state.lock().unwrap().interval = interval;
+        self
+    }
+
+    /// Set the time a check may take before it fails, default is 1s.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.inner.state.lock().unwrap().timeout = timeout;
+        self
+    }
+
+    /// Set the number of consecutive failed checks that removes an instance, default is 3, and zero means 1.
+    pub fn with_failure_threshold(self, failure_threshold: u32) -> Self {
+        self.inner.state.lock().unwrap().failure_threshold = failure_threshold.max(1);
+        self
+    }
+
+    /// Subscribes the inner discover and starts the checks in the background.
+    fn start_watching(&self) {
+        if self.inner.watching.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            self.inner.watching.store(false, Ordering::Release);
+            return;
+        };
+        if let Some(mut receiver) = self.inner.discover.watch(None) {
+            let weak = Arc::downgrade(&self.inner);
+            runtime.spawn(async move {
+                while let Some(discovery) = receiver.next().await {
+                    let Some(inner) = weak.upgrade() else {
+                        return;
+                    };
+                    inner.update(|state| {
+                        if let Some((upstream, _)) = state.keys.get_mut(&discovery.key) {
+                            *upstream = discovery.instance_cluster;
+                        }
+                    });
+                }
+            });
+        }
+        runtime.spawn(check_loop(Arc::downgrade(&self.inner), self.inner.state.lock().unwrap().interval));
+    }
+}
+
+impl HealthState {
+    fn is_healthy(&self, instance: &Instance) -> bool {
+        self.failures.get(&instance.address).map_or(true, |failures| *failures < self.failure_threshold)
+    }
+
+    fn filter(&self, instance_cluster: &InstanceCluster) -> InstanceCluster {
+        instance_cluster.clone().map_instances(|instances| instances.into_iter().filter(|instance| self.is_healthy(instance)).collect())
+    }
+}
+
+impl<D, C: HealthCheck> Inner<D, C> {
+    /// Applies the change, and broadcasts the keys whose emitted instance cluster changed.
+    fn update(&self, f: impl FnOnce(&mut HealthState)) {
+        let changes = {
+            let mut state = self.state.lock().unwrap();
+            f(&mut state);
+            let mut changes = Vec::new();
+            let filtered = state.keys.iter().map(|(key, (upstream, _))| (key.clone(), state.filter(upstream))).collect::<Vec<_>>();
+            for (key, instance_cluster) in filtered {
+                let (_, emitted) = state.keys.get_mut(&key).unwrap();
+                if *emitted != instance_cluster {
+                    *emitted = instance_cluster.clone();
+                    changes.push(Discovery { key, instance_cluster });
+                }
+            }
+            changes
+        };
+        for discovery in changes {
+            self.broadcaster.send(discovery);
+        }
+    }
+
+    /// Probes every discovered instance once.
+    async fn check_all(&self) {
+        let (instances, timeout) = {
+            let state = self.state.lock().unwrap();
+            let mut seen = HashSet::new();
+            let instances = state
+                .keys
+                .values()
+                .filter_map(|(upstream, _)| upstream.instances())
+                .flatten()
+                .filter(|instance| seen.insert(instance.address.clone()))
+                .cloned()
+                .collect::<Vec<_>>();
+            (instances, state.timeout)
+        };
+        let results = futures::future::join_all(instances.iter().map(|instance| async move {
+            let healthy = tokio::time::timeout(timeout, self.check.check(instance)).await.unwrap_or(false);
+            (instance.address.clone(), healthy)
+        }))
+        .await;
+        self.update(|state| {
+            let mut failures = HashMap::with_capacity(results.len());
+            for (address, healthy) in results {
+                let prev = state.failures.get(&address).copied().unwrap_or_default();
+                if healthy {
+                    if prev >= state.failure_threshold {
+                        info!("[LOGIMESH] instance {address} is healthy again");
+                    }
+                    continue;
+                }
+                debug!("[LOGIMESH] health check of instance {address} failed");
+                if prev + 1 == state.failure_threshold {
+                    info!("[LOGIMESH] instance {address} is unhealthy after {} failed checks", prev + 1);
+                }
+                failures.insert(address, prev.saturating_add(1));
+            }
+            // Instances that are no longer discovered are forgotten.
+            state.failures = failures;
+        });
+    }
+}
+
+async fn check_loop<D, C: HealthCheck>(weak: Weak<Inner<D, C>>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(inner) = weak.upgrade() else {
+            return;
+        };
+        inner.check_all().await;
+    }
+}
+
+impl<D: Discover, C: HealthCheck> Discover for HealthCheckDiscover<D, C> {
+    fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send {
+        async move {
+            self.start_watching();
+            let discovery = self.inner.discover.discover(endpoint).await?;
+            let mut state = self.inner.state.lock().unwrap();
+            let instance_cluster = state.filter(&discovery.instance_cluster);
+            state.keys.insert(discovery.key.clone(), (discovery.instance_cluster, instance_cluster.clone()));
+            Ok(Discovery { key: discovery.key, instance_cluster })
+        }
+    }
+
+    fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+        self.start_watching();
+        Some(self.inner.broadcaster.subscribe(keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::discover::FixedDiscover;
+    use crate::health::{Health, HealthService};
+    use crate::server::{BaseChannel, Channel};
+    use tokio::net::TcpListener;
+
+    async fn recv(receiver: &mut Receiver<Discovery>) -> InstanceCluster {
+        tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap().instance_cluster
+    }
+
+    #[tokio::test]
+    async fn eject_and_restore() {
+        let alive = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_address = dead.local_addr().unwrap();
+        drop(dead);
+        let fixed = FixedDiscover::from_address(vec![alive.local_addr().unwrap().into(), dead_address.into()]);
+        let discover = HealthCheckDiscover::new(fixed).with_interval(Duration::from_millis(10)).with_failure_threshold(2);
+        let mut receiver = discover.watch(None).unwrap();
+        let InstanceCluster::Rpc(instances) = discover.discover(&Endpoint::new("hello")).await.unwrap().instance_cluster else {
+            panic!("expect rpc")
+        };
+        assert_eq!(instances.len(), 2);
+
+        assert_eq!(recv(&mut receiver).await, InstanceCluster::Rpc(vec![instances[0].clone()]));
+        let _dead = TcpListener::bind(dead_address).await.unwrap();
+        assert_eq!(recv(&mut receiver).await, InstanceCluster::Rpc(instances));
+    }
+
+    #[tokio::test]
+    async fn rpc_health_check() {
+        let listener = crate::transport::tcp::listen("127.0.0.1:0", <HealthService as Health>::TRANSPORT_CODEC.to_fn()).await.unwrap();
+        let address = listener.local_addr();
+        let health = HealthService::new();
+        let serve = health.clone().logimesh_serve();
+        tokio::spawn(listener.filter_map(|transport| async { transport.ok() }).for_each(move |transport| {
+            let serve = serve.clone();
+            async move {
+                tokio::spawn(BaseChannel::with_defaults(transport).execute(serve).for_each(|fut| async {
+                    tokio::spawn(fut);
+                }));
+            }
+        }));
+        let instance = Instance {
+            address: "127.0.0.1:1".parse().unwrap(),
+            weight: 1,
+            tags: [(HEALTH_ADDRESS_TAG.into(), address.to_string().into())].into_iter().collect(),
+        };
+        assert!(RpcHealthCheck::new().check(&instance).await);
+        assert!(!RpcHealthCheck::new().with_service("hello").check(&instance).await);
+        health.set_status("hello", ServingStatus::Serving);
+        assert!(RpcHealthCheck::new().with_service("hello").check(&instance).await);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[tokio::test]
+    async fn rpc_health_check_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("logimesh-health-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = crate::transport::unix::listen(&path, <HealthService as Health>::TRANSPORT_CODEC.to_fn()).await.unwrap();
+        let serve = HealthService::new().logimesh_serve();
+        tokio::spawn(listener.filter_map(|transport| async { transport.ok() }).for_each(move |transport| {
+            let serve = serve.clone();
+            async move {
+                tokio::spawn(BaseChannel::with_defaults(transport).execute(serve).for_each(|fut| async {
+                    tokio::spawn(fut);
+                }));
+            }
+        }));
+        let instance = Instance {
+            address: format!("unix:{}", path.display()).parse().unwrap(),
+            weight: 1,
+            tags: Default::default(),
+        };
+        assert!(TcpConnectCheck.check(&instance).await);
+        assert!(RpcHealthCheck::new().check(&instance).await);
+        std::fs::remove_file(&path).unwrap();
+        assert!(!TcpConnectCheck.check(&instance).await);
+    }
+}
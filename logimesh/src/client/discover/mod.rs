@@ -16,18 +16,45 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::future::Future;
 use std::sync::Arc;
+mod broadcast;
+mod caching;
+mod compose;
+mod consul;
 mod dummy;
+mod file;
+mod filter;
 mod fixed;
+mod health;
+mod registry;
+mod subset;
 use super::ClientError;
 use core::marker::Send;
+pub use broadcast::DiscoveryBroadcaster;
+pub use caching::CachingDiscover;
+pub use compose::{FallbackDiscover, FirstDiscover, MergeDiscover};
+pub(crate) use consul::{consul_tags, ConsulAgent};
+pub use consul::{ConsulDatacenter, ConsulDiscover, ConsulTags};
 pub use dummy::DummyDiscover;
-pub use fixed::FixedDiscover;
+pub use file::FileDiscover;
+pub use filter::{FilterDiscover, Selector, SelectorParseError, TagSelector};
+pub use fixed::{FixedDiscover, FixedDiscoverHandle};
+pub use health::{HealthCheck, HealthCheckDiscover, RpcHealthCheck, TcpConnectCheck};
+pub use registry::RegistryDiscover;
+pub use subset::{Subset, SubsetDiscover};
 
 /// [`Discover`] is the most basic trait for Discover.
 pub trait Discover: Send + Sync + 'static {
     /// `discover` allows to request an endpoint and return a discover future.
     fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send;
     /// `watch` should return a [`async_broadcast::Receiver`] which can be used to subscribe [`Discovery`].
+    ///
+    /// When `keys` is `Some`, the receiver should only yield the discoveries of those endpoint keys,
+    /// and `None` subscribes all keys. Each call should return a channel of its own, so that a discover
+    /// shared by many clients does not send every client the changes of the others.
+    /// [`DiscoveryBroadcaster`] implements this contract.
+    ///
+    /// Subscribers should still ignore the discoveries of other keys, as a discover may not be able to filter them.
+    /// `None` means the discover never changes.
     fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>>;
 }
 
@@ -52,12 +79,60 @@ pub struct Discovery {
 }
 
 /// Local Serve or remote instance cluster.
+///
+/// The enum is non-exhaustive since [`InstanceCluster::Split`] was added in 0.2, matches on it need a wildcard arm,
+/// or can use [`InstanceCluster::instances`] and [`InstanceCluster::rpc_percent`].
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum InstanceCluster {
     /// Use local procedure call.
     Lpc,
     /// Use remote procedure call, and carry the instance list.
     Rpc(Vec<Arc<Instance>>),
+    /// Split the calls between local and remote procedure calls, and carry the instance list of the remote ones.
+    Split {
+        /// Percentage of the calls that are remote procedure calls, from 0 to 100.
+        rpc_percent: u8,
+        /// The instance list.
+        instances: Vec<Arc<Instance>>,
+    },
+}
+
+impl InstanceCluster {
+    /// Returns a [`InstanceCluster::Split`] of the instances, or a [`InstanceCluster::Rpc`] from 100 percent.
+    pub fn split(rpc_percent: u8, instances: Vec<Arc<Instance>>) -> Self {
+        if rpc_percent >= 100 {
+            Self::Rpc(instances)
+        } else {
+            Self::Split { rpc_percent, instances }
+        }
+    }
+
+    /// Returns the percentage of the calls that are remote procedure calls.
+    pub fn rpc_percent(&self) -> u8 {
+        match self {
+            Self::Lpc => 0,
+            Self::Rpc(_) => 100,
+            Self::Split { rpc_percent, .. } => (*rpc_percent).min(100),
+        }
+    }
+
+    /// Returns the instance list, `None` for local procedure calls only.
+    pub fn instances(&self) -> Option<&[Arc<Instance>]> {
+        match self {
+            Self::Lpc => None,
+            Self::Rpc(instances) | Self::Split { instances, .. } => Some(instances),
+        }
+    }
+
+    /// Maps the instance list, and keeps the local procedure calls.
+    pub fn map_instances(self, f: impl FnOnce(Vec<Arc<Instance>>) -> Vec<Arc<Instance>>) -> Self {
+        match self {
+            Self::Lpc => Self::Lpc,
+            Self::Rpc(instances) => Self::Rpc(f(instances)),
+            Self::Split { rpc_percent, instances } => Self::Split { rpc_percent, instances: f(instances) },
+        }
+    }
 }
 
 #[cfg(test)]
@@ -86,4 +161,36 @@ mod tests {
         ]);
         assert_eq!(resp.instance_cluster, expected);
     }
+
+    #[test]
+    fn test_fixed_discover_handle() {
+        let discover = FixedDiscover::from_address_str(vec!["127.0.0.1:8000"]).unwrap();
+        let handle = discover.handle();
+        let mut receiver = discover.watch(None).unwrap();
+        let endpoint = Endpoint::new("hello");
+        futures::executor::block_on(async { discover.discover(&endpoint).await }).unwrap();
+
+        handle.set_lpc("hello", true);
+        let discovery = receiver.try_recv().unwrap();
+        assert_eq!(discovery.key, "hello");
+        assert_eq!(discovery.instance_cluster, InstanceCluster::Lpc);
+
+        let added = Arc::new(Instance {
+            address: "127.0.0.2:9000".parse().unwrap(),
+            weight: 2,
+            tags: Default::default(),
+        });
+        // The instance list is patched silently while the key uses local calls.
+        handle.patch_key("hello", vec![added.clone()], &["127.0.0.1:8000".parse().unwrap()]);
+        assert!(receiver.try_recv().is_err());
+
+        handle.set_lpc("hello", false);
+        assert_eq!(receiver.try_recv().unwrap().instance_cluster, InstanceCluster::Rpc(vec![added.clone()]));
+
+        // Keys with their own instance cluster are not affected by the default one.
+        handle.replace(InstanceCluster::Lpc);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(handle.instance_cluster("hello"), InstanceCluster::Rpc(vec![added]));
+        assert_eq!(handle.instance_cluster("other"), InstanceCluster::Lpc);
+    }
 }
@@ -0,0 +1,173 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! Discover backed by the built-in registry.
+
+use super::{Discover, Discovery, DiscoveryBroadcaster, InstanceCluster};
+use crate::client::ClientError;
+use crate::component::Endpoint;
+use crate::registry::{RegistryClient, Snapshot};
+use async_broadcast::Receiver;
+use faststr::FastStr;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::net::ToSocketAddrs;
+use tracing::warn;
+
+/// Default time a watch request waits for changes on the registry.
+const DEFAULT_WAIT: Duration = Duration::from_secs(30);
+/// Time to wait before retrying a failed watch request.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// [`RegistryDiscover`] discovers instances from the built-in [`crate::registry::Registry`].
+///
+/// Every discovered endpoint key is long-polled in the background, and its changes are pushed through [`Discover::watch`].
+#[derive(Clone)]
+pub struct RegistryDiscover {
+    inner: Arc<Inner>,
+    wait: Duration,
+}
+
+struct Inner {
+    client: RegistryClient,
+    broadcaster: DiscoveryBroadcaster,
+    watching: Mutex<HashSet<FastStr>>,
+}
+
+impl RegistryDiscover {
+    /// Creates a new [`RegistryDiscover`] with the registry client.
+    pub fn new(client: RegistryClient) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client,
+                broadcaster: DiscoveryBroadcaster::new(),
+                watching: Mutex::new(HashSet::new()),
+            }),
+            wait: DEFAULT_WAIT,
+        }
+    }
+
+    /// Connects to the registry listening on the address.
+    pub async fn connect(address: impl ToSocketAddrs) -> Result<Self, ClientError> {
+        let client = crate::registry::connect(address)
+            .await
+            .map_err(|e| ClientError::Discover(format!("failed to connect the registry: {e}").into()))?;
+        Ok(Self::new(client))
+    }
+
+    /// Set the time a watch request waits for changes on the registry, default is 30s.
+    pub fn with_wait(mut self, wait: Duration) -> Self {
+        self.wait = wait;
+        self
+    }
+
+    fn spawn_watch(&self, key: FastStr, revision: u64) {
+        if !self.inner.watching.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        tokio::spawn(watch(Arc::downgrade(&self.inner), key, revision, self.wait));
+    }
+}
+
+impl Discover for RegistryDiscover {
+    fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send {
+        async move {
+            let key = endpoint.key();
+            match self.inner.client.watch(crate::context::current(), key.to_string(), 0, 0).await {
+                Ok(snapshot) => {
+                    self.spawn_watch(key.clone(), snapshot.revision);
+                    Ok(to_discovery(key, &snapshot))
+                },
+                Err(e) => {
+                    // Keep watching with an unknown revision, so that the key is reported once the registry is back.
+                    self.spawn_watch(key, u64::MAX);
+                    Err(ClientError::Discover(format!("failed to query the registry: {e}").into()))
+                },
+            }
+        }
+    }
+
+    fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+        Some(self.inner.broadcaster.subscribe(keys))
+    }
+}
+
+async fn watch(weak: Weak<Inner>, key: FastStr, mut revision: u64, wait: Duration) {
+    loop {
+        let Some(client) = weak.upgrade().map(|inner| inner.client.clone()) else {
+            return;
+        };
+        let mut ctx = crate::context::current();
+        ctx.deadline = Instant::now() + wait + RETRY_INTERVAL;
+        match client.watch(ctx, key.to_string(), revision, wait.as_millis() as u64).await {
+            Ok(snapshot) => {
+                if snapshot.revision != revision {
+                    revision = snapshot.revision;
+                    let Some(inner) = weak.upgrade() else {
+                        return;
+                    };
+                    inner.broadcaster.send(to_discovery(key.clone(), &snapshot));
+                }
+            },
+            Err(e) => {
+                warn!("[LOGIMESH] failed to watch the registry: {e:?}");
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            },
+        }
+    }
+}
+
+fn to_discovery(key: FastStr, snapshot: &Snapshot) -> Discovery {
+    let instances = snapshot
+        .registrations
+        .iter()
+        .filter_map(|registration| match registration.to_instance() {
+            Ok(instance) => Some(Arc::new(instance)),
+            Err(e) => {
+                warn!("[LOGIMESH] invalid address {} in the registry: {e}", registration.address);
+                None
+            },
+        })
+        .collect();
+    Discovery {
+        key,
+        instance_cluster: InstanceCluster::Rpc(instances),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{Registration, Registry, RegistryLease, RegistryService};
+    use crate::server::{BaseChannel, Channel};
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn discover_and_watch() {
+        let (client_transport, server_transport) = crate::transport::channel::unbounded();
+        tokio::spawn(BaseChannel::with_defaults(server_transport).execute(RegistryService::new().logimesh_serve()).for_each(|fut| async {
+            tokio::spawn(fut);
+        }));
+        let client = RegistryClient::new(Default::default(), client_transport).spawn();
+        let discover = RegistryDiscover::new(client.clone()).with_wait(Duration::from_millis(200));
+        let mut receiver = discover.watch(None).unwrap();
+
+        let endpoint = Endpoint::new("hello");
+        assert_eq!(discover.discover(&endpoint).await.unwrap().instance_cluster, InstanceCluster::Rpc(vec![]));
+
+        let registration = Registration::new(&endpoint, "127.0.0.1:8888".parse().unwrap(), 1);
+        let lease = RegistryLease::grant(client, registration.clone(), Duration::from_secs(10)).await.unwrap();
+        let discovery = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(discovery.key, "hello");
+        assert_eq!(discovery.instance_cluster, InstanceCluster::Rpc(vec![Arc::new(registration.to_instance().unwrap())]));
+
+        assert!(lease.revoke().await.unwrap());
+        let discovery = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(discovery.instance_cluster, InstanceCluster::Rpc(vec![]));
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Deterministic subsetting of instances.
+
+use super::{Discover, Discovery, DiscoveryBroadcaster, Instance, InstanceCluster};
+use crate::client::balance::hash_key;
+use crate::client::ClientError;
+use crate::component::Endpoint;
+use crate::net::Address;
+use async_broadcast::Receiver;
+use faststr::FastStr;
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Deterministic subset of the instances for a client, as described in the Google SRE book.
+///
+/// The instances are split into subsets of the same size, and the clients are grouped in rounds of as many clients
+/// as there are subsets. Every round orders the instances differently, and each of its clients takes another subset,
+/// so that the clients of a round together use every instance once, and the instances get the same number of clients.
+/// The order of a round is the order of the hashes of the instance addresses, so an added or removed instance only
+/// moves the subsets by one instance, unless the number of subsets changes. The hash is [`hash_key`], which is stable,
+/// so the clients built with other Rust releases agree on the subsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subset {
+    client_id: u64,
+    size: usize,
+}
+
+impl Subset {
+    /// Returns the subset of at most `size` instances of the client.
+    ///
+    /// The client ids should be consecutive numbers, e.g. the index of a pod in a stateful set.
+    pub fn new(client_id: u64, size: usize) -> Self {
+        Self { client_id, size: size.max(1) }
+    }
+
+    /// Keeps the instances of the subset in their order, local calls are not subset.
+    pub fn select(&self, instance_cluster: InstanceCluster) -> InstanceCluster {
+        instance_cluster.map_instances(|instances| self.select_instances(instances))
+    }
+
+    fn select_instances(&self, instances: Vec<Arc<Instance>>) -> Vec<Arc<Instance>> {
+        if instances.len() <= self.size {
+            return instances;
+        }
+        let subset_count = (instances.len() / self.size) as u64;
+        let round = self.client_id / subset_count;
+        let subset_id = (self.client_id % subset_count) as usize;
+        let mut order: Vec<(u64, &Address)> = instances.iter().map(|instance| (hash_key((round, &instance.address)), &instance.address)).collect();
+        order.sort_unstable_by_key(|(hash, _)| *hash);
+        let subset: HashSet<Address> = order[subset_id * self.size..(subset_id + 1) * self.size].iter().map(|(_, address)| (*address).clone()).collect();
+        instances.into_iter().filter(|instance| subset.contains(&instance.address)).collect()
+    }
+}
+
+/// [`SubsetDiscover`] keeps only the [`Subset`] of the instances of the client, so that every client dials a limited
+/// number of instances instead of all of them, and subsets every change reported by the inner [`Discover::watch`] again.
+pub struct SubsetDiscover<D> {
+    inner: Arc<Inner<D>>,
+}
+
+struct Inner<D> {
+    discover: D,
+    subset: Subset,
+    last: Mutex<HashMap<FastStr, InstanceCluster>>,
+    broadcaster: DiscoveryBroadcaster,
+    watching: AtomicBool,
+}
+
+impl<D> Clone for SubsetDiscover<D> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<D: Discover> SubsetDiscover<D> {
+    /// Creates a new [`SubsetDiscover`] wrapping the discover, which keeps the subset of the client.
+    pub fn new(discover: D, subset: Subset) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                discover,
+                subset,
+                last: Mutex::new(HashMap::new()),
+                broadcaster: DiscoveryBroadcaster::new(),
+                watching: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Subscribes the inner discover, and subsets its changes in the background.
+    fn start_watching(&self) {
+        if self.inner.watching.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            self.inner.watching.store(false, Ordering::Release);
+            return;
+        };
+        if let Some(mut receiver) = self.inner.discover.watch(None) {
+            let weak = Arc::downgrade(&self.inner);
+            runtime.spawn(async move {
+                while let Some(discovery) = receiver.next().await {
+                    let Some(inner) = weak.upgrade() else {
+                        return;
+                    };
+                    inner.update(discovery);
+                }
+            });
+        }
+    }
+}
+
+impl<D> Inner<D> {
+    fn update(&self, discovery: Discovery) {
+        let discovery = {
+            let mut last = self.last.lock().unwrap();
+            let Some(last) = last.get_mut(&discovery.key) else {
+                return;
+            };
+            let instance_cluster = self.subset.select(discovery.instance_cluster);
+            if *last == instance_cluster {
+                return;
+            }
+            *last = instance_cluster.clone();
+            Discovery { key: discovery.key, instance_cluster }
+        };
+        self.broadcaster.send(discovery);
+    }
+}
+
+impl<D: Discover> Discover for SubsetDiscover<D> {
+    fn discover<'s>(&'s self, endpoint: &'s Endpoint) -> impl Future<Output = Result<Discovery, ClientError>> + Send {
+        async move {
+            self.start_watching();
+            let discovery = self.inner.discover.discover(endpoint).await?;
+            let instance_cluster = self.inner.subset.select(discovery.instance_cluster);
+            self.inner.last.lock().unwrap().insert(discovery.key.clone(), instance_cluster.clone());
+            Ok(Discovery { key: discovery.key, instance_cluster })
+        }
+    }
+
+    fn watch(&self, keys: Option<&[FastStr]>) -> Option<Receiver<Discovery>> {
+        self.start_watching();
+        Some(self.inner.broadcaster.subscribe(keys))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::discover::FixedDiscover;
+    use std::time::Duration;
+
+    fn instances(ports: std::ops::Range<u16>) -> Vec<Arc<Instance>> {
+        ports
+            .map(|port| {
+                Arc::new(Instance {
+                    address: format!("127.0.0.1:{port}").parse().unwrap(),
+                    weight: 1,
+                    tags: Default::default(),
+                })
+            })
+            .collect()
+    }
+
+    fn addresses(instance_cluster: InstanceCluster) -> HashSet<Address> {
+        instance_cluster.instances().expect("unexpected local calls").iter().map(|instance| instance.address.clone()).collect()
+    }
+
+    #[test]
+    fn spread_and_stable_subsets() {
+        let all = instances(8000..8010);
+        let mut clients: HashMap<Address, usize> = HashMap::new();
+        for client_id in 0..30 {
+            let subset = addresses(Subset::new(client_id, 3).select(InstanceCluster::Rpc(all.clone())));
+            assert_eq!(subset.len(), 3);
+            for address in subset {
+                *clients.entry(address).or_default() += 1;
+            }
+        }
+        // Every round of 3 clients uses 9 of the 10 instances.
+        assert_eq!(clients.len(), 10);
+        assert!(clients.values().all(|&count| (5..=10).contains(&count)), "{clients:?}");
+
+        // The clients of a round take distinct instances.
+        let round: Vec<_> = (0..3).map(|client_id| addresses(Subset::new(client_id, 3).select(InstanceCluster::Rpc(all.clone())))).collect();
+        assert!(round[0].is_disjoint(&round[1]) && round[1].is_disjoint(&round[2]) && round[0].is_disjoint(&round[2]));
+
+        // An added instance moves a subset by one instance at most.
+        for client_id in 0..30 {
+            let subset = Subset::new(client_id, 3);
+            let before = addresses(subset.select(InstanceCluster::Rpc(all.clone())));
+            let after = addresses(subset.select(InstanceCluster::Rpc(instances(8000..8011))));
+            assert!(before.intersection(&after).count() >= 2, "client {client_id}");
+        }
+        assert_eq!(Subset::new(7, 3).select(InstanceCluster::Lpc), InstanceCluster::Lpc);
+        assert_eq!(Subset::new(7, 30).select(InstanceCluster::Rpc(all.clone())), InstanceCluster::Rpc(all));
+    }
+
+    #[tokio::test]
+    async fn subset_watch_updates() {
+        let fixed = FixedDiscover::new(InstanceCluster::Rpc(instances(8000..8004)));
+        let subset = Subset::new(1, 2);
+        let discover = SubsetDiscover::new(fixed.clone(), subset);
+        let mut receiver = discover.watch(None).unwrap();
+        let endpoint = Endpoint::new("hello");
+        let discovered = discover.discover(&endpoint).await.unwrap().instance_cluster;
+        assert_eq!(discovered, subset.select(InstanceCluster::Rpc(instances(8000..8004))));
+
+        fixed.handle().patch_key("hello", instances(8004..8008), &[]);
+        let discovery = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(discovery.instance_cluster, subset.select(InstanceCluster::Rpc(instances(8000..8008))));
+        assert_eq!(addresses(discovery.instance_cluster).len(), 2);
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Runtime control of the call mode.
+
+use rand::Rng;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use tracing::info;
+
+/// The mode of the calls of [`crate::client::lrcall::LRCall`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CallMode {
+    /// The discovery of the endpoint decides between the local and remote calls, see
+    /// [`crate::client::discover::InstanceCluster`].
+    #[default]
+    Discovery,
+    /// Local calls only.
+    Local,
+    /// Remote calls only, the local call still serves when there is no connection.
+    Remote,
+}
+
+impl CallMode {
+    fn from_u8(mode: u8) -> Self {
+        match mode {
+            1 => CallMode::Local,
+            2 => CallMode::Remote,
+            _ => CallMode::Discovery,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            CallMode::Discovery => 0,
+            CallMode::Local => 1,
+            CallMode::Remote => 2,
+        }
+    }
+}
+
+/// A cloneable handle to pin the calls of a [`crate::client::lrcall::LRCall`] to local or remote calls at runtime,
+/// e.g. during an incident, and to read the current mode.
+///
+/// A pinned mode also takes precedence over the shadow mode, see [`crate::client::lrcall::Shadow`].
+///
+/// # Example:
+/// ```
+/// use logimesh::client::lrcall::{CallMode, LRControl};
+///
+/// // The control is returned by `logimesh_control` of a generated client.
+/// fn pin_local(control: &LRControl) {
+///     control.set_mode(CallMode::Local);
+///     assert_eq!(control.mode(), CallMode::Local);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct LRControl {
+    mode: Arc<AtomicU8>,
+    rpc_percent: Arc<AtomicU8>,
+}
+
+impl LRControl {
+    pub(super) fn new() -> Self {
+        Self {
+            mode: Arc::new(AtomicU8::new(CallMode::Discovery.as_u8())),
+            rpc_percent: Arc::new(AtomicU8::new(0)),
+        }
+    }
+
+    /// Returns the current mode of the calls.
+    pub fn mode(&self) -> CallMode {
+        CallMode::from_u8(self.mode.load(Ordering::Acquire))
+    }
+
+    /// Pins the calls to the mode, [`CallMode::Discovery`] returns them to the discovered mode.
+    ///
+    /// [`CallMode::Remote`] is not strict: while there is no connection, e.g. after the discovery switched to local
+    /// calls, the local call serves, and a failed remote call still falls back to the local call when the fallback
+    /// function of [`crate::client::lrcall::Builder::with_fallback_fn`] allows it.
+    pub fn set_mode(&self, mode: CallMode) {
+        if CallMode::from_u8(self.mode.swap(mode.as_u8(), Ordering::AcqRel)) != mode {
+            info!("[LOGIMESH] the call mode is set to {mode:?}");
+        }
+    }
+
+    /// Returns the discovered percentage of the calls that are remote calls, which applies in [`CallMode::Discovery`].
+    pub fn rpc_percent(&self) -> u8 {
+        self.rpc_percent.load(Ordering::Acquire)
+    }
+
+    /// Sets the percentage of the calls that are remote calls until the discovery of the endpoint changes again.
+    pub fn set_rpc_percent(&self, rpc_percent: u8) {
+        self.rpc_percent.store(rpc_percent.min(100), Ordering::Release);
+    }
+
+    /// Returns whether the call is a remote call.
+    pub(super) fn use_rpc(&self) -> bool {
+        match self.mode() {
+            CallMode::Local => false,
+            CallMode::Remote => true,
+            CallMode::Discovery => match self.rpc_percent() {
+                0 => false,
+                100 => true,
+                rpc_percent => rand::thread_rng().gen_range(0..100) < rpc_percent,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_by_rpc_percent() {
+        let control = LRControl::new();
+        control.set_rpc_percent(30);
+        let remote = (0..10000).filter(|_| control.use_rpc()).count();
+        assert!((2700..=3300).contains(&remote), "{remote}");
+
+        // A pinned mode takes precedence over the percentage.
+        control.set_mode(CallMode::Local);
+        assert!((0..100).all(|_| !control.use_rpc()));
+        control.set_mode(CallMode::Remote);
+        assert!((0..100).all(|_| control.use_rpc()));
+    }
+}
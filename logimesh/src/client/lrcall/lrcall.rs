@@ -13,14 +13,17 @@ use crate::client::discover::{Discover, Discovery, Instance, InstanceCluster};
 use crate::client::ClientError;
 use crate::component::Component;
 use crate::net::Address;
+use crate::RequestName;
 use crate::server::Serve;
 use crate::transport::codec::Codec;
+use super::shadow::timed;
+use super::{CallMode, LRControl, RetryPolicy, Shadow, ShadowMode};
 use futures_util::{select, FutureExt};
 use std::collections::HashSet;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 use std::usize;
-use tokio::sync::Notify;
+use tokio::sync::{oneshot, Notify};
 use tracing::{trace, warn};
 
 /// A full client stbu config.
@@ -48,8 +51,21 @@ where
     pub(crate) max_frame_len: usize,
     /// A callback function for judging whether to re-initiate the request.
     pub(crate) retry_fn: Option<RF>,
+    /// A callback function returning the retry policy of the remote calls of the request.
+    pub(crate) retry_policy_fn: Option<RetryPolicyFn<S::Req>>,
+    /// A callback function for judging whether a failed remote call falls back to the local call.
+    pub(crate) fallback_fn: Option<FallbackFn<S::Req>>,
+    /// Shadow mode mirroring the calls to the other side.
+    pub(crate) shadow: Option<Arc<Shadow<S::Req, S::Resp>>>,
 }
 
+/// A callback function returning the retry policy of the remote calls of the request, e.g. per method.
+pub type RetryPolicyFn<Req> = Box<dyn Fn(&Req) -> Option<RetryPolicy> + Send + Sync>;
+
+/// A callback function for judging whether a failed remote call falls back to the local call,
+/// which is given the request, e.g. to decide per method, and the error of the last attempt.
+pub type FallbackFn<Req> = Box<dyn Fn(&Req, &RpcError) -> bool + Send + Sync>;
+
 /// A full client stbu config extend.
 #[non_exhaustive]
 pub struct ConfigExt {
@@ -111,6 +127,9 @@ where
             core_config: Default::default(),
             max_frame_len: usize::MAX,
             retry_fn: None,
+            retry_policy_fn: None,
+            fallback_fn: None,
+            shadow: None,
         }
     }
     /// Set transport serde codec
@@ -139,6 +158,37 @@ where
         self.retry_fn = Some(retry_fn);
         self
     }
+    /// Set a callback function returning the retry policy of the remote calls of the request, see [`RetryPolicy`].
+    ///
+    /// The remote calls of a request with a policy are retried by the policy instead of the retry function.
+    pub fn with_retry_policy_fn(mut self, retry_policy_fn: impl Fn(&S::Req) -> Option<RetryPolicy> + Send + Sync + 'static) -> Self {
+        self.retry_policy_fn = Some(Box::new(retry_policy_fn));
+        self
+    }
+    /// Set a callback function for judging whether a failed remote call falls back to the local call.
+    ///
+    /// It is called once the retries, if any, are exhausted, e.g. to fall back on [`RpcError::Shutdown`]
+    /// or [`RpcError::DeadlineExceeded`]. Without it, the remote errors are returned.
+    pub fn with_fallback_fn(mut self, fallback_fn: impl Fn(&S::Req, &RpcError) -> bool + Send + Sync + 'static) -> Self {
+        self.fallback_fn = Some(Box::new(fallback_fn));
+        self
+    }
+    /// Set shadow mode, which serves the caller from the side of the mode and mirrors the calls to the other side,
+    /// see [`Shadow`]. It takes precedence over the discovered split of the calls.
+    ///
+    /// The local shadow calls run in the background, so the futures of the component must be [`Send`].
+    pub fn with_shadow(mut self, shadow: Shadow<S::Req, S::Resp>) -> Self
+    where
+        S: Serve<serve(..): Send> + Clone + Send + Sync,
+    {
+        let serve = self.component.serve.clone();
+        let shadow = shadow.with_serve_local_fn(Box::new(move |ctx, request| {
+            let serve = serve.clone();
+            async move { serve.serve(ctx, request).await.map_err(RpcError::Server) }.boxed()
+        }));
+        self.shadow = Some(Arc::new(shadow));
+        self
+    }
     /// Set some default extension configurations.
     pub fn with_config_ext(mut self, config_ext: ConfigExt) -> Self {
         self.core_config.max_in_flight_requests = config_ext.max_in_flight_requests;
@@ -159,7 +209,7 @@ where
         LRCall {
             config: self,
             notify: Arc::new(Notify::new()),
-            use_rpc: Arc::new(AtomicBool::new(false)),
+            control: LRControl::new(),
         }
         .warm_up()
         .await
@@ -178,7 +228,8 @@ where
 {
     config: Builder<S, D, LB, RF>,
     notify: Arc<Notify>,
-    use_rpc: Arc<AtomicBool>,
+    /// The mode of the calls, and the percentage of the calls that are remote calls.
+    control: LRControl,
 }
 
 impl<S, D, LB, RF> LRCall<S, D, LB, RF>
@@ -194,12 +245,12 @@ where
         let discovery = self.config.discover.discover(&self.config.component.endpoint).await?;
         let mut channels: Vec<RpcChannel<S>> = Vec::new();
         match discovery.instance_cluster {
-            InstanceCluster::Lpc => self.use_rpc.store(false, Ordering::Release),
-            InstanceCluster::Rpc(instances) => {
-                for instance in instances {
-                    self.use_rpc.store(true, Ordering::Release);
+            InstanceCluster::Lpc => self.control.set_rpc_percent(0),
+            instance_cluster => {
+                self.control.set_rpc_percent(instance_cluster.rpc_percent());
+                for instance in instance_cluster.instances().unwrap_or_default() {
                     let channel = RpcChannel::new(RpcConfig {
-                        instance,
+                        instance: instance.clone(),
                         transport_codec: self.config.transport_codec,
                         core_config: self.config.core_config.clone(),
                         max_frame_len: self.config.max_frame_len,
@@ -218,13 +269,14 @@ where
         }
         let prev = channels.clone();
         self.config.load_balance.start_balance(channels);
-        if let Some(mut recv_change) = self.config.discover.watch(None) {
+        let key = self.config.component.endpoint.key();
+        if let Some(mut recv_change) = self.config.discover.watch(Some(std::slice::from_ref(&key))) {
             let load_balance = self.config.load_balance.clone();
             let transport_codec = self.config.transport_codec;
             let core_config = self.config.core_config.clone();
             let max_frame_len = self.config.max_frame_len;
             let notify = self.notify.clone();
-            let use_rpc = self.use_rpc.clone();
+            let control = self.control.clone();
             tokio::spawn(async move {
                 let mut prev = prev;
                 loop {
@@ -233,12 +285,23 @@ where
                             return;
                         },
                         discovery = recv_change.recv().fuse() => match discovery {
+                            Ok(Discovery{key: other,..}) if other != key => {
+                                trace!("[LOGIMESH] ignore the discovery of another endpoint key: {other}");
+                            },
                             Ok(Discovery{instance_cluster:InstanceCluster::Lpc,..}) => {
-                                use_rpc.store(false, Ordering::Release);
-                                prev.clear();
+                                control.set_rpc_percent(0);
+                                if !prev.is_empty() {
+                                    load_balance.rebalance(Some(RpcChange {
+                                        all: vec![],
+                                        added: vec![],
+                                        updated: vec![],
+                                        removed: prev.drain(..).map(|c| c.config().instance.address.clone()).collect(),
+                                    }));
+                                }
                             },
-                            Ok(Discovery{instance_cluster:InstanceCluster::Rpc(next),..}) => {
-                                use_rpc.store(true, Ordering::Release);
+                            Ok(Discovery{instance_cluster,..}) => {
+                                control.set_rpc_percent(instance_cluster.rpc_percent());
+                                let next = instance_cluster.instances().unwrap_or_default().to_vec();
                                 match Self::diff_and_dial(transport_codec, &core_config, max_frame_len, &mut prev, next).await {
                                     Ok(changes) => {
                                         load_balance.rebalance(changes);
@@ -255,6 +318,22 @@ where
         Ok(self)
     }
 
+    /// Returns the percentage of the calls that are remote calls, see [`InstanceCluster::Split`].
+    pub fn rpc_percent(&self) -> u8 {
+        self.control.rpc_percent()
+    }
+
+    /// Sets the percentage of the calls that are remote calls, e.g. to carve a component out gradually,
+    /// until the discovery of the endpoint changes again. Zero makes local calls only.
+    pub fn set_rpc_percent(&self, rpc_percent: u8) {
+        self.control.set_rpc_percent(rpc_percent)
+    }
+
+    /// Returns a cloneable handle to pin the calls to local or remote calls at runtime, see [`LRControl`].
+    pub fn control(&self) -> LRControl {
+        self.control.clone()
+    }
+
     async fn diff_and_dial(transport_codec: Codec, core_config: &Config, max_frame_len: usize, prev: &mut Vec<RpcChannel<S>>, next: Vec<Arc<Instance>>) -> Result<Option<RpcChange<S>>, ClientError>
     where
         S: Serve,
@@ -347,27 +426,39 @@ where
 
     type Resp = S::Resp;
 
-    // TODO: Think about whether to fallback to LPC after RPC fails?
     async fn call(&self, ctx: crate::context::Context, request: Self::Req) -> Result<Self::Resp, RpcError> {
-        let use_rpc = self.use_rpc.load(Ordering::Acquire);
+        match (&self.config.shadow, self.control.mode()) {
+            (Some(shadow), CallMode::Discovery) => self.call_shadow(shadow, ctx, request).await,
+            _ => self.call_side(ctx, request, self.control.use_rpc()).await,
+        }
+    }
+}
+
+impl<S, D, LB, RF> LRCall<S, D, LB, RF>
+where
+    S: Serve + Clone + 'static,
+    S::Req: crate::serde::Serialize + Send + Clone + 'static,
+    S::Resp: for<'de> crate::serde::Deserialize<'de> + Send + 'static,
+    D: Discover,
+    LB: LoadBalance<S>,
+    RF: Fn(&Result<S::Resp, RpcError>, u32) -> bool,
+{
+    /// Calls the remote instances or the local service, with the retries and the fallback.
+    async fn call_side(&self, ctx: crate::context::Context, request: S::Req, use_rpc: bool) -> Result<S::Resp, RpcError> {
+        if let Some(retry_policy) = self.config.retry_policy_fn.as_ref().filter(|_| use_rpc).and_then(|retry_policy_fn| (retry_policy_fn)(&request)) {
+            return self.call_retrying(retry_policy, ctx, request).await;
+        }
         if let Some(retry_fn) = &self.config.retry_fn {
             if use_rpc {
-                let mut picker = self.config.load_balance.get_picker();
+                let mut picker = self.config.load_balance.get_picker_for(&ctx, &request);
                 for i in 1.. {
                     if let Some(channel) = picker.next() {
-                        let result = channel.call(ctx, request.clone()).await;
-                        if let Err(RpcError::Shutdown) = result {
-                            // TODO: Change to asynchronous processing
-                            match channel.reconnent().await {
-                                Ok(_) => trace!("[LOGIMESH] success to reconnect"),
-                                Err(e) => warn!("[LOGIMESH] failed to reconnect: {e:?}"),
-                            };
-                        }
-                        if (retry_fn)(&result, i) {
+                        let result = self.call_remote(&channel, ctx, request.clone()).await;
+                        if (retry_fn)(&result, i) && Instant::now() < ctx.deadline {
                             trace!("[LOGIMESH] Retrying on attempt {i}");
                             continue;
                         }
-                        return result;
+                        return self.fallback(ctx, request, result).await;
                     } else {
                         // When there is no connection, fallback to local call (LPC)
                         warn!("[LOGIMESH] As there is no connection, fallback to local call.");
@@ -378,7 +469,7 @@ where
             } else {
                 for i in 1.. {
                     let result = self.config.component.serve.call(ctx, request.clone()).await;
-                    if (retry_fn)(&result, i) {
+                    if (retry_fn)(&result, i) && Instant::now() < ctx.deadline {
                         trace!("[LOGIMESH] Retrying on attempt {i}");
                         continue;
                     }
@@ -388,17 +479,13 @@ where
             }
         } else {
             if use_rpc {
-                let mut picker = self.config.load_balance.get_picker();
+                let mut picker = self.config.load_balance.get_picker_for(&ctx, &request);
                 if let Some(channel) = picker.next() {
-                    let result = channel.call(ctx, request).await;
-                    if let Err(RpcError::Shutdown) = result {
-                        // TODO: Change to asynchronous processing
-                        match channel.reconnent().await {
-                            Ok(_) => trace!("[LOGIMESH] success to reconnect"),
-                            Err(e) => warn!("[LOGIMESH] failed to reconnect: {e:?}"),
-                        };
+                    if self.config.fallback_fn.is_none() {
+                        return self.call_remote(&channel, ctx, request).await;
                     }
-                    return result;
+                    let result = self.call_remote(&channel, ctx, request.clone()).await;
+                    return self.fallback(ctx, request, result).await;
                 } else {
                     // When there is no connection, fallback to local call (LPC)
                     warn!("[LOGIMESH] As there is no connection, fallback to local call.");
@@ -409,6 +496,106 @@ where
             }
         }
     }
+
+    /// Calls the remote instances, and retries the failed calls by the retry policy.
+    async fn call_retrying(&self, retry_policy: RetryPolicy, ctx: crate::context::Context, request: S::Req) -> Result<S::Resp, RpcError> {
+        let mut picker = self.config.load_balance.get_picker_for(&ctx, &request);
+        let Some(mut channel) = picker.next() else {
+            // When there is no connection, fallback to local call (LPC)
+            warn!("[LOGIMESH] As there is no connection, fallback to local call.");
+            return self.config.component.serve.call(ctx, request).await;
+        };
+        let started = Instant::now();
+        for attempt in 1.. {
+            let result = self.call_remote(&channel, ctx, request.clone()).await;
+            let Some(backoff) = result.as_ref().err().and_then(|err| retry_policy.retry(attempt, err, started, ctx.deadline)) else {
+                return self.fallback(ctx, request, result).await;
+            };
+            trace!("[LOGIMESH] Retrying {} on attempt {attempt} after {backoff:?}", request.name());
+            tokio::time::sleep(backoff).await;
+            // The retries go to the next channels, and to the last one once they are all tried.
+            if let Some(next) = picker.next() {
+                channel = next;
+            }
+        }
+        unreachable!("[LOGIMESH] Wow, that was a lot of attempts!");
+    }
+
+    /// Serves the caller from the side of the shadow mode, and compares the response with the one of the other side.
+    async fn call_shadow(&self, shadow: &Arc<Shadow<S::Req, S::Resp>>, ctx: crate::context::Context, request: S::Req) -> Result<S::Resp, RpcError> {
+        let Some(permit) = shadow.acquire() else {
+            return self.call_side(ctx, request, shadow.mode() == ShadowMode::Remote).await;
+        };
+        match shadow.mode() {
+            ShadowMode::Local => {
+                let Some(channel) = self.config.load_balance.get_picker_for(&ctx, &request).next() else {
+                    trace!("[LOGIMESH] As there is no connection, skip the shadow call.");
+                    return self.config.component.serve.call(ctx, request).await;
+                };
+                let (served_tx, served_rx) = oneshot::channel();
+                tokio::spawn({
+                    let (shadow, load_balance, shadow_request) = (shadow.clone(), self.config.load_balance.clone(), request.clone());
+                    async move {
+                        let _permit = permit;
+                        let (result, shadow_latency) = timed(channel.call(ctx, shadow_request.clone())).await;
+                        load_balance.report(&channel, &result);
+                        // The caller is gone when the served result is not sent.
+                        if let Ok((served, served_latency)) = served_rx.await {
+                            shadow.compare(&shadow_request, &served, served_latency, &result, shadow_latency);
+                        }
+                    }
+                });
+                let (served, served_latency) = timed(self.config.component.serve.call(ctx, request)).await;
+                let _ = served_tx.send((shadow.clone_result(&served), served_latency));
+                served
+            },
+            ShadowMode::Remote => {
+                let Some(local) = shadow.serve_local(ctx, request.clone()) else {
+                    return self.call_side(ctx, request, true).await;
+                };
+                let (served_tx, served_rx) = oneshot::channel();
+                tokio::spawn({
+                    let (shadow, shadow_request) = (shadow.clone(), request.clone());
+                    async move {
+                        let _permit = permit;
+                        let (result, shadow_latency) = timed(local).await;
+                        // The caller is gone when the served result is not sent.
+                        if let Ok((served, served_latency)) = served_rx.await {
+                            shadow.compare(&shadow_request, &served, served_latency, &result, shadow_latency);
+                        }
+                    }
+                });
+                let (served, served_latency) = timed(self.call_side(ctx, request, true)).await;
+                let _ = served_tx.send((shadow.clone_result(&served), served_latency));
+                served
+            },
+        }
+    }
+
+    /// Calls the remote instance of the channel, and reports the result to the load balance.
+    async fn call_remote(&self, channel: &RpcChannel<S>, ctx: crate::context::Context, request: S::Req) -> Result<S::Resp, RpcError> {
+        let result = channel.call(ctx, request).await;
+        self.config.load_balance.report(channel, &result);
+        if let Err(RpcError::Shutdown) = result {
+            // TODO: Change to asynchronous processing
+            match channel.reconnent().await {
+                Ok(_) => trace!("[LOGIMESH] success to reconnect"),
+                Err(e) => warn!("[LOGIMESH] failed to reconnect: {e:?}"),
+            };
+        }
+        result
+    }
+
+    /// Calls the local service instead when the remote call failed and the fallback function allows it.
+    async fn fallback(&self, ctx: crate::context::Context, request: S::Req, result: Result<S::Resp, RpcError>) -> Result<S::Resp, RpcError> {
+        match (&result, &self.config.fallback_fn) {
+            (Err(err), Some(fallback_fn)) if (fallback_fn)(&request, err) => {
+                warn!("[LOGIMESH] As the remote call failed, fallback to local call: {err:?}");
+                self.config.component.serve.call(ctx, request).await
+            },
+            _ => result,
+        }
+    }
 }
 
 impl<S, D, LB, RF> Drop for LRCall<S, D, LB, RF>
@@ -424,3 +611,239 @@ where
         self.notify.notify_waiters();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::balance::{RandomBalance, RoundRobinBalance};
+    use crate::client::discover::FixedDiscover;
+    use crate::client::lrcall::RpcErrorKind;
+    use crate::component::Endpoint;
+    use crate::health::{Health, HealthRequest, HealthResponse, HealthService, ServeHealth, ServingStatus};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    /// Adds an offset to the value, which differs between the local and the remote service.
+    #[crate::component(derive = [Clone, PartialEq])]
+    trait Offset {
+        async fn add(value: u32) -> u32;
+    }
+
+    #[derive(Clone)]
+    struct OffsetService(u32);
+
+    impl Offset for OffsetService {
+        async fn add(self, _: crate::context::Context, value: u32) -> u32 {
+            value + self.0
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback_after_remote_failure() {
+        // The listener never answers, so the remote calls time out.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let discover = FixedDiscover::from_address_str(vec![listener.local_addr().unwrap().to_string()]).unwrap();
+        let lrcall = Builder::<ServeHealth<HealthService>, _, _, fn(&Result<HealthResponse, RpcError>, u32) -> bool>::new(
+            Component {
+                serve: HealthService::new().logimesh_serve(),
+                endpoint: Endpoint::new("health"),
+            },
+            discover,
+            RoundRobinBalance::new(),
+        )
+        .with_fallback_fn(|request, error| matches!((request, error), (HealthRequest::Check { service }, RpcError::DeadlineExceeded) if service.is_empty()))
+        .try_spawn()
+        .await
+        .unwrap();
+        let call = |service: &str| {
+            let mut ctx = crate::context::current();
+            ctx.deadline = Instant::now() + Duration::from_millis(100);
+            lrcall.call(ctx, HealthRequest::Check { service: service.to_string() })
+        };
+        assert!(matches!(call("").await, Ok(HealthResponse::Check(ServingStatus::Serving))));
+        // The method of another service does not fall back.
+        assert!(matches!(call("other").await, Err(RpcError::DeadlineExceeded)));
+    }
+
+    #[tokio::test]
+    async fn split_local_and_remote_calls() {
+        // The listener never answers, so the remote calls time out.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let discover = FixedDiscover::from_address_str(vec![listener.local_addr().unwrap().to_string()]).unwrap();
+        discover.handle().set_rpc_percent(Endpoint::new("health").key(), 0);
+        let lrcall = Builder::<ServeHealth<HealthService>, _, _, fn(&Result<HealthResponse, RpcError>, u32) -> bool>::new(
+            Component {
+                serve: HealthService::new().logimesh_serve(),
+                endpoint: Endpoint::new("health"),
+            },
+            discover,
+            RoundRobinBalance::new(),
+        )
+        .try_spawn()
+        .await
+        .unwrap();
+        let call = || {
+            let mut ctx = crate::context::current();
+            ctx.deadline = Instant::now() + Duration::from_millis(100);
+            lrcall.call(ctx, HealthRequest::Check { service: String::new() })
+        };
+        assert_eq!(lrcall.rpc_percent(), 0);
+        assert!(matches!(call().await, Ok(HealthResponse::Check(ServingStatus::Serving))));
+        lrcall.set_rpc_percent(100);
+        assert!(matches!(call().await, Err(RpcError::DeadlineExceeded)));
+
+        // A pinned mode takes precedence over the split.
+        let control = lrcall.control();
+        control.set_mode(CallMode::Local);
+        assert_eq!(lrcall.control().mode(), CallMode::Local);
+        assert!(matches!(call().await, Ok(HealthResponse::Check(ServingStatus::Serving))));
+        control.set_mode(CallMode::Discovery);
+        assert!(matches!(call().await, Err(RpcError::DeadlineExceeded)));
+    }
+
+    #[tokio::test]
+    async fn remote_pin_after_local_discovery() {
+        // The listener never answers, so the remote calls time out.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let discover = FixedDiscover::from_address_str(vec![listener.local_addr().unwrap().to_string()]).unwrap();
+        let handle = discover.handle();
+        let lrcall = Builder::<ServeHealth<HealthService>, _, _, fn(&Result<HealthResponse, RpcError>, u32) -> bool>::new(
+            Component {
+                serve: HealthService::new().logimesh_serve(),
+                endpoint: Endpoint::new("health"),
+            },
+            discover,
+            RandomBalance::new(),
+        )
+        .try_spawn()
+        .await
+        .unwrap();
+        let call = || {
+            let mut ctx = crate::context::current();
+            ctx.deadline = Instant::now() + Duration::from_millis(100);
+            lrcall.call(ctx, HealthRequest::Check { service: String::new() })
+        };
+        lrcall.control().set_mode(CallMode::Remote);
+        assert!(matches!(call().await, Err(RpcError::DeadlineExceeded)));
+
+        // The local discovery removes the connections, so the remote pin finds no connection.
+        handle.set_lpc(Endpoint::new("health").key(), true);
+        while lrcall.rpc_percent() != 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(matches!(call().await, Ok(HealthResponse::Check(ServingStatus::Serving))));
+    }
+
+    #[tokio::test]
+    async fn shadow_calls() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            crate::tokio_tcp_listen!(OffsetService(1), crate::server::TcpConfig::new(address));
+        });
+        while tokio::net::TcpStream::connect(address).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let lrcall = |shadow| {
+            Builder::<ServeOffset<OffsetService>, _, _, fn(&Result<OffsetResponse, RpcError>, u32) -> bool>::new(
+                Component {
+                    serve: OffsetService(0).logimesh_serve(),
+                    endpoint: Endpoint::new("offset"),
+                },
+                FixedDiscover::from_address_str(vec![address.to_string()]).unwrap(),
+                RoundRobinBalance::new(),
+            )
+            .with_shadow(shadow)
+            .try_spawn()
+        };
+        let reports = Arc::new(Mutex::new(Vec::new()));
+
+        // The local call serves the caller, and the remote response is compared in the background.
+        let shadow = Shadow::new(ShadowMode::Local, |local, remote| local == remote).with_report_fn({
+            let reports = reports.clone();
+            move |report| reports.lock().unwrap().push((report.mode, report.matched))
+        });
+        let local = lrcall(shadow).await.unwrap();
+        assert_eq!(local.call(crate::context::current(), OffsetRequest::Add { value: 1 }).await.unwrap(), OffsetResponse::Add(1));
+        while reports.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*reports.lock().unwrap(), vec![(ShadowMode::Local, false)]);
+
+        // The remote call serves the caller, the comparator allows for the offset.
+        let shadow = Shadow::new(ShadowMode::Remote, |remote, local| matches!((remote, local), (OffsetResponse::Add(remote), OffsetResponse::Add(local)) if *remote == local + 1)).with_report_fn({
+            let reports = reports.clone();
+            move |report| reports.lock().unwrap().push((report.mode, report.matched))
+        });
+        let remote = lrcall(shadow).await.unwrap();
+        assert_eq!(remote.call(crate::context::current(), OffsetRequest::Add { value: 1 }).await.unwrap(), OffsetResponse::Add(2));
+        while reports.lock().unwrap().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(reports.lock().unwrap()[1..], [(ShadowMode::Remote, true)]);
+
+        // The calls which are not sampled are not mirrored.
+        let shadow = Shadow::new(ShadowMode::Local, |local, remote| local == remote).with_sample_rate(0.0).with_report_fn({
+            let reports = reports.clone();
+            move |report| reports.lock().unwrap().push((report.mode, report.matched))
+        });
+        let unsampled = lrcall(shadow).await.unwrap();
+        assert_eq!(unsampled.call(crate::context::current(), OffsetRequest::Add { value: 1 }).await.unwrap(), OffsetResponse::Add(1));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(reports.lock().unwrap().len(), 2);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn retry_on_next_channel() {
+        // The first instance closes the connections, so its calls fail.
+        let closing = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closing_address = closing.local_addr().unwrap();
+        let closing = tokio::spawn(async move {
+            while let Ok((stream, _)) = closing.accept().await {
+                drop(stream);
+            }
+        });
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            crate::tokio_tcp_listen!(OffsetService(1), crate::server::TcpConfig::new(address).with_max_channels_per_key(2));
+        });
+        while tokio::net::TcpStream::connect(address).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let lrcall = |idempotent| {
+            Builder::<ServeOffset<OffsetService>, _, _, fn(&Result<OffsetResponse, RpcError>, u32) -> bool>::new(
+                Component {
+                    serve: OffsetService(0).logimesh_serve(),
+                    endpoint: Endpoint::new("offset"),
+                },
+                FixedDiscover::from_address_str(vec![closing_address.to_string(), address.to_string()]).unwrap(),
+                RoundRobinBalance::new(),
+            )
+            .with_retry_policy_fn(move |_| {
+                Some(RetryPolicy::new().with_max_attempts(2).with_retry_on(&[RpcErrorKind::Send, RpcErrorKind::Shutdown]).with_idempotent(idempotent))
+            })
+            .try_spawn()
+        };
+        // Whichever instance is picked first, the call is served remotely.
+        let idempotent = lrcall(true).await.unwrap();
+        for _ in 0..4 {
+            assert_eq!(idempotent.call(crate::context::current(), OffsetRequest::Add { value: 1 }).await.unwrap(), OffsetResponse::Add(2));
+        }
+
+        // The server may have served the request when the connection is lost, which is not retried.
+        let not_idempotent = lrcall(false).await.unwrap();
+        let mut failed = 0;
+        for _ in 0..4 {
+            match not_idempotent.call(crate::context::current(), OffsetRequest::Add { value: 1 }).await {
+                Ok(response) => assert_eq!(response, OffsetResponse::Add(2)),
+                Err(err) => {
+                    assert!(matches!(err, RpcError::Shutdown), "{err:?}");
+                    failed += 1;
+                },
+            }
+        }
+        assert_eq!(failed, 2);
+        closing.abort();
+        server.abort();
+    }
+}
@@ -5,6 +5,12 @@
 // https://opensource.org/licenses/MIT.
 //! Provides a Stub trait, implemented by types that can call remote services.
 
+pub use control::*;
 pub use lrcall::*;
+pub use retry::*;
+pub use shadow::*;
 
+mod control;
 mod lrcall;
+mod retry;
+mod shadow;
@@ -0,0 +1,225 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Retry policy of the remote calls.
+
+use crate::client::core::RpcError;
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+/// The kind of a [`RpcError`], see [`RetryPolicy::with_retry_on`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RpcErrorKind {
+    /// [`RpcError::Shutdown`]
+    Shutdown,
+    /// [`RpcError::Send`]
+    Send,
+    /// [`RpcError::Channel`]
+    Channel,
+    /// [`RpcError::DeadlineExceeded`]
+    DeadlineExceeded,
+    /// [`RpcError::Server`]
+    Server,
+}
+
+impl RpcErrorKind {
+    /// Returns the kind of the error.
+    pub fn of(err: &RpcError) -> Self {
+        match err {
+            RpcError::Shutdown => RpcErrorKind::Shutdown,
+            RpcError::Send(_) => RpcErrorKind::Send,
+            RpcError::Channel(_) => RpcErrorKind::Channel,
+            RpcError::DeadlineExceeded => RpcErrorKind::DeadlineExceeded,
+            RpcError::Server(_) => RpcErrorKind::Server,
+        }
+    }
+
+    /// Returns whether the request of a failed call was surely not sent to the server.
+    ///
+    /// Only [`RpcErrorKind::Send`] is, [`RpcErrorKind::Shutdown`] is also returned for the requests in flight
+    /// when the connection is lost, which the server may have served.
+    pub fn is_unsent(self) -> bool {
+        matches!(self, RpcErrorKind::Send)
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// The retry policy of the remote calls of a method, which [`crate::client::lrcall::LRCall`] enforces.
+///
+/// A failed call is retried on the next channel of the picker, after an exponential backoff with jitter, while
+/// the attempts, the retry time and the deadline of the context allow it. A retry has to start before the deadline,
+/// and the total retry time, which counts from the first attempt, is capped by [`RetryPolicy::with_max_retry_time`].
+///
+/// Only the retryable kinds of errors are retried, and only those of the requests that were surely not sent,
+/// see [`RpcErrorKind::is_unsent`], unless the method is idempotent.
+///
+/// The methods of a component set their policy with the `#[retry(...)]` attribute:
+/// ```
+/// #[logimesh::component]
+/// trait Counter {
+///     #[retry(max_attempts = 3, initial_backoff_ms = 20, max_backoff_ms = 500, jitter = 0.5, retry_on = [Shutdown, DeadlineExceeded], idempotent)]
+///     async fn get() -> u64;
+///     #[retry(max_attempts = 2)]
+///     async fn increment() -> u64;
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    jitter: f64,
+    max_retry_time: Option<Duration>,
+    retry_on: u8,
+    idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RetryPolicy {
+    /// Returns a [`RetryPolicy`] of 3 attempts, which retries the requests that were not sent.
+    pub fn new() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_retry_time: None,
+            retry_on: RpcErrorKind::Send.bit(),
+            idempotent: false,
+        }
+    }
+
+    /// Set the maximum number of attempts, including the first call, default is 3, and 1 disables the retries.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Set the backoff before the first retry, default is 10ms.
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Set the maximum backoff, default is 1s.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Set the factor of the backoff of every retry, default is 2.0.
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Set the ratio of the backoff which is random, default is 0.5, so that the backoff is between half
+    /// and all of its exponential value.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set the maximum time from the first attempt within which a retry may start, default is only the deadline
+    /// of the context.
+    pub fn with_max_retry_time(mut self, max_retry_time: Duration) -> Self {
+        self.max_retry_time = Some(max_retry_time);
+        self
+    }
+
+    /// Set the kinds of errors which are retried, default is [`RpcErrorKind::Send`].
+    ///
+    /// The other kinds are only retried for the idempotent methods, see [`RetryPolicy::with_idempotent`].
+    pub fn with_retry_on(mut self, kinds: &[RpcErrorKind]) -> Self {
+        self.retry_on = kinds.iter().fold(0, |retry_on, kind| retry_on | kind.bit());
+        self
+    }
+
+    /// Set whether the method is idempotent, so that the requests which may have been served are also retried,
+    /// default is false.
+    pub fn with_idempotent(mut self, idempotent: bool) -> Self {
+        self.idempotent = idempotent;
+        self
+    }
+
+    /// Returns the maximum number of attempts.
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Returns whether the method is idempotent.
+    pub fn is_idempotent(&self) -> bool {
+        self.idempotent
+    }
+
+    /// Returns whether the error is retried.
+    pub fn is_retryable(&self, err: &RpcError) -> bool {
+        let kind = RpcErrorKind::of(err);
+        self.retry_on & kind.bit() != 0 && (self.idempotent || kind.is_unsent())
+    }
+
+    /// Returns the backoff before the retry of the failed attempt, with its jitter.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self.initial_backoff.as_secs_f64() * self.multiplier.powi(attempt.saturating_sub(1).min(i32::MAX as u32) as i32);
+        let backoff = exponential.min(self.max_backoff.as_secs_f64());
+        Duration::from_secs_f64(backoff * (1.0 - self.jitter * rand::thread_rng().gen::<f64>()))
+    }
+
+    /// Returns the backoff before the retry of the failed attempt, or `None` when it is not retried.
+    pub(super) fn retry(&self, attempt: u32, err: &RpcError, started: Instant, deadline: Instant) -> Option<Duration> {
+        if attempt >= self.max_attempts || !self.is_retryable(err) {
+            return None;
+        }
+        let backoff = self.backoff(attempt);
+        let retry_at = Instant::now() + backoff;
+        if retry_at >= deadline || self.max_retry_time.is_some_and(|max_retry_time| retry_at > started + max_retry_time) {
+            return None;
+        }
+        Some(backoff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_within_limits() {
+        let policy = RetryPolicy::new()
+            .with_initial_backoff(Duration::from_millis(100))
+            .with_max_backoff(Duration::from_millis(300))
+            .with_jitter(0.0);
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(300));
+        let jittered = policy.with_jitter(0.5).backoff(2);
+        assert!((Duration::from_millis(100)..=Duration::from_millis(200)).contains(&jittered), "{jittered:?}");
+
+        let (now, deadline) = (Instant::now(), Instant::now() + Duration::from_secs(10));
+        let unsent = || RpcError::Send("connection refused".into());
+        assert!(policy.retry(1, &unsent(), now, deadline).is_some());
+        assert!(policy.retry(3, &unsent(), now, deadline).is_none());
+        // The request may have been served, which is retried for idempotent methods only.
+        assert!(policy.retry(1, &RpcError::Shutdown, now, deadline).is_none());
+        let policy = policy.with_retry_on(&[RpcErrorKind::Send, RpcErrorKind::Shutdown, RpcErrorKind::DeadlineExceeded]);
+        assert!(policy.retry(1, &RpcError::Shutdown, now, deadline).is_none());
+        assert!(policy.retry(1, &RpcError::DeadlineExceeded, now, deadline).is_none());
+        assert!(policy.with_idempotent(true).retry(1, &RpcError::Shutdown, now, deadline).is_some());
+        assert!(policy.with_idempotent(true).retry(1, &RpcError::DeadlineExceeded, now, deadline).is_some());
+        // The retry starts within the deadline and the retry time.
+        assert!(policy.retry(1, &unsent(), now, now + Duration::from_millis(50)).is_none());
+        assert!(policy.with_max_retry_time(Duration::from_millis(50)).retry(1, &unsent(), now, deadline).is_none());
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! Shadow traffic between the local and remote calls.
+
+use crate::client::core::RpcError;
+use crate::context::Context;
+use crate::RequestName;
+use futures::future::BoxFuture;
+use rand::Rng;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{trace, warn};
+
+/// Default maximum number of the shadow calls in flight.
+const DEFAULT_MAX_CONCURRENCY: usize = 100;
+
+/// The side of the calls which serves the caller in shadow mode, the same request is mirrored to the other side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowMode {
+    /// The local call serves the caller, and the remote call is the shadow.
+    Local,
+    /// The remote call serves the caller, and the local call is the shadow.
+    Remote,
+}
+
+/// A comparison of the served call and its shadow call, see [`Shadow::with_report_fn`].
+#[derive(Debug)]
+pub struct ShadowReport<'a, Req, Resp> {
+    /// The request of both calls.
+    pub request: &'a Req,
+    /// The side which served the caller.
+    pub mode: ShadowMode,
+    /// The result returned to the caller.
+    pub served: &'a Result<Resp, RpcError>,
+    /// The result of the shadow call.
+    pub shadow: &'a Result<Resp, RpcError>,
+    /// The latency of the served call.
+    pub served_latency: Duration,
+    /// The latency of the shadow call.
+    pub shadow_latency: Duration,
+    /// Whether the responses are equal for the comparator, or both calls failed.
+    pub matched: bool,
+}
+
+/// A callback function for the reports of the shadow calls.
+pub type ShadowReportFn<Req, Resp> = Box<dyn Fn(&ShadowReport<'_, Req, Resp>) + Send + Sync>;
+
+/// A function serving the local call of the shadow in the background.
+pub(super) type ServeLocalFn<Req, Resp> = Box<dyn Fn(Context, Req) -> BoxFuture<'static, Result<Resp, RpcError>> + Send + Sync>;
+
+/// Shadow mode of [`crate::client::lrcall::LRCall`], which mirrors the requests to the side that does not serve
+/// the caller, and compares the responses, e.g. to check that a remote deployment behaves as the in-process component
+/// before switching to it.
+///
+/// The shadow call runs in the background, so the caller gets the served response without waiting for it.
+/// Without a remote channel, the local call serves the caller and nothing is mirrored in [`ShadowMode::Local`].
+///
+/// Only a sample of the calls is mirrored, see [`Shadow::with_sample_rate`], and a call is not mirrored while
+/// the maximum number of shadow calls are in flight, see [`Shadow::with_max_concurrency`].
+///
+/// The mismatches are logged as warnings unless a report function is set.
+///
+/// # Example:
+/// ```
+/// use logimesh::client::lrcall::{Shadow, ShadowMode};
+///
+/// let shadow: Shadow<String, u32> = Shadow::new(ShadowMode::Local, |local, remote| local == remote)
+///     .with_sample_rate(0.1)
+///     .with_report_fn(|report| {
+///         if !report.matched {
+///             eprintln!("{} differs, remote took {:?}", report.request, report.shadow_latency);
+///         }
+///     });
+/// ```
+pub struct Shadow<Req, Resp> {
+    mode: ShadowMode,
+    compare_fn: Box<dyn Fn(&Resp, &Resp) -> bool + Send + Sync>,
+    report_fn: Option<ShadowReportFn<Req, Resp>>,
+    clone_fn: fn(&Resp) -> Resp,
+    sample_rate: f64,
+    concurrency: Arc<Semaphore>,
+    serve_local_fn: Option<ServeLocalFn<Req, Resp>>,
+}
+
+impl<Req, Resp: Clone> Shadow<Req, Resp> {
+    /// Returns a [`Shadow`] serving the caller from the side of the mode, which compares the served response
+    /// with the shadow response by the comparator.
+    ///
+    /// The response of a component derives [`Clone`] with `#[logimesh::component(derive = [Clone])]`.
+    pub fn new(mode: ShadowMode, compare_fn: impl Fn(&Resp, &Resp) -> bool + Send + Sync + 'static) -> Self {
+        Self {
+            mode,
+            compare_fn: Box::new(compare_fn),
+            report_fn: None,
+            clone_fn: Resp::clone,
+            sample_rate: 1.0,
+            concurrency: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENCY)),
+            serve_local_fn: None,
+        }
+    }
+}
+
+impl<Req, Resp> Shadow<Req, Resp> {
+    /// Set a callback function for the reports of all the shadow calls, e.g. to record the latency deltas.
+    pub fn with_report_fn(mut self, report_fn: impl Fn(&ShadowReport<'_, Req, Resp>) + Send + Sync + 'static) -> Self {
+        self.report_fn = Some(Box::new(report_fn));
+        self
+    }
+
+    /// Set the ratio of the calls which are mirrored, default is 1.0.
+    pub fn with_sample_rate(mut self, sample_rate: f64) -> Self {
+        self.sample_rate = sample_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set the maximum number of the shadow calls in flight, default is 100, the calls beyond it are not mirrored.
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.concurrency = Arc::new(Semaphore::new(max_concurrency.min(Semaphore::MAX_PERMITS)));
+        self
+    }
+
+    /// Returns the side which serves the caller.
+    pub fn mode(&self) -> ShadowMode {
+        self.mode
+    }
+
+    /// Set the function serving the local shadow calls.
+    pub(super) fn with_serve_local_fn(mut self, serve_local_fn: ServeLocalFn<Req, Resp>) -> Self {
+        self.serve_local_fn = Some(serve_local_fn);
+        self
+    }
+
+    /// Returns the local call of the request to run in the background.
+    pub(super) fn serve_local(&self, ctx: Context, request: Req) -> Option<BoxFuture<'static, Result<Resp, RpcError>>> {
+        self.serve_local_fn.as_ref().map(|serve_local_fn| (serve_local_fn)(ctx, request))
+    }
+
+    /// Returns a permit for a shadow call if the call is sampled and the shadow calls in flight allow it.
+    pub(super) fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if self.sample_rate < 1.0 && rand::thread_rng().gen::<f64>() >= self.sample_rate {
+            return None;
+        }
+        self.concurrency.clone().try_acquire_owned().ok()
+    }
+
+    /// Returns a copy of the served result for the background comparison.
+    pub(super) fn clone_result(&self, result: &Result<Resp, RpcError>) -> Result<Resp, RpcError> {
+        match result {
+            Ok(resp) => Ok((self.clone_fn)(resp)),
+            Err(err) => Err(clone_error(err)),
+        }
+    }
+}
+
+impl<Req: RequestName, Resp> Shadow<Req, Resp> {
+    /// Compares the served result with the shadow result, and reports it.
+    pub(super) fn compare(&self, request: &Req, served: &Result<Resp, RpcError>, served_latency: Duration, shadow: &Result<Resp, RpcError>, shadow_latency: Duration) {
+        let matched = match (served, shadow) {
+            (Ok(served), Ok(shadow)) => (self.compare_fn)(served, shadow),
+            (Err(_), Err(_)) => true,
+            _ => false,
+        };
+        let report = ShadowReport {
+            request,
+            mode: self.mode,
+            served,
+            shadow,
+            served_latency,
+            shadow_latency,
+            matched,
+        };
+        if let Some(report_fn) = &self.report_fn {
+            return (report_fn)(&report);
+        }
+        if matched {
+            trace!("[LOGIMESH] shadow call of {} matched, served in {served_latency:?}, shadow in {shadow_latency:?}", request.name());
+        } else {
+            warn!(
+                "[LOGIMESH] shadow call of {} mismatched, served {} in {served_latency:?}, shadow {} in {shadow_latency:?}",
+                request.name(),
+                if served.is_ok() { "ok" } else { "err" },
+                if shadow.is_ok() { "ok" } else { "err" },
+            );
+        }
+    }
+}
+
+/// Returns the output of the future with the time it took.
+pub(super) async fn timed<F: Future>(future: F) -> (F::Output, Duration) {
+    let start = Instant::now();
+    let output = future.await;
+    (output, start.elapsed())
+}
+
+/// Returns a copy of the error, the errors of the transport are kept as messages.
+fn clone_error(err: &RpcError) -> RpcError {
+    match err {
+        RpcError::Shutdown => RpcError::Shutdown,
+        RpcError::DeadlineExceeded => RpcError::DeadlineExceeded,
+        RpcError::Server(err) => RpcError::Server(err.clone()),
+        err => RpcError::Send(err.to_string().into()),
+    }
+}
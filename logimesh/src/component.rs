@@ -9,7 +9,8 @@
 
 use crate::net::Address;
 use faststr::FastStr;
-use metainfo::FastStrMap;
+use std::any::TypeId;
+use std::collections::HashMap;
 use std::fmt::Debug;
 
 const DEFAULT_MAP_CAPACITY: usize = 10;
@@ -22,8 +23,11 @@ pub struct Component<S> {
     pub endpoint: Endpoint,
 }
 
+/// The tags of an [`Endpoint`], keyed by the type of the tag.
+pub type EndpointTags = HashMap<TypeId, FastStr>;
+
 /// Endpoint contains the information of the service.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 #[non_exhaustive]
 pub struct Endpoint {
     /// `service_name` is the most important information, which is used by the service discovering.
@@ -34,7 +38,7 @@ pub struct Endpoint {
     ///
     /// Users can use `tags` to store custom data, such as the datacenter name or the region name,
     /// which can be used by the service discoverer.
-    pub tags: FastStrMap,
+    pub tags: EndpointTags,
     /// A callback function used for creating keys.
     pub key_maker: Option<fn(&Self) -> FastStr>,
 }
@@ -46,7 +50,7 @@ impl Endpoint {
         Self {
             service_name: service_name.into(),
             address: None,
-            tags: FastStrMap::with_capacity(DEFAULT_MAP_CAPACITY),
+            tags: EndpointTags::with_capacity(DEFAULT_MAP_CAPACITY),
             key_maker: None,
         }
     }
@@ -77,19 +81,19 @@ impl Endpoint {
     /// Insert a tag into this `Endpoint`.
     #[inline]
     pub fn insert<T: Send + Sync + 'static>(&mut self, val: FastStr) {
-        self.tags.insert::<T>(val);
+        self.tags.insert(TypeId::of::<T>(), val);
     }
 
     /// Check if `Endpoint` tags contain entry
     #[inline]
     pub fn contains<T: 'static>(&self) -> bool {
-        self.tags.contains::<T>()
+        self.tags.contains_key(&TypeId::of::<T>())
     }
 
     /// Get a reference to a tag previously inserted on this `Endpoint`.
     #[inline]
     pub fn get<T: 'static>(&self) -> Option<&FastStr> {
-        self.tags.get::<T>()
+        self.tags.get(&TypeId::of::<T>())
     }
 
     /// Sets the address.
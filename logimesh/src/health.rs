@@ -0,0 +1,108 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! A built-in health component, which reports whether the services of a server are serving.
+//!
+//! Serve [`HealthService`] next to the components, and advertise its address in the
+//! [`HEALTH_ADDRESS_TAG`] of the instances, so that [`crate::client::discover::RpcHealthCheck`] can probe them.
+//!
+//! # Example:
+//! ```no_run
+//! # async fn run() {
+//! use logimesh::health::{Health, HealthService, ServingStatus};
+//!
+//! let health = HealthService::new();
+//! health.set_status("hello", ServingStatus::NotServing);
+//! logimesh::tokio_tcp_listen!(health.clone(), logimesh::server::TcpConfig::new("127.0.0.1:8889"));
+//! # }
+//! ```
+
+use crate::client::core::Config;
+use crate::context::Context;
+#[cfg(target_family = "unix")]
+use crate::net::address::connect_unix;
+use crate::net::Address;
+use crate::transport::tcp;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+#[cfg(target_family = "unix")]
+use tarpc::tokio_util::codec::LengthDelimitedCodec;
+use tokio::net::ToSocketAddrs;
+
+/// The [`crate::client::discover::Instance::tags`] key of the address serving [`Health`].
+pub const HEALTH_ADDRESS_TAG: &str = "health_address";
+
+/// The health component.
+#[crate::component]
+pub trait Health {
+    /// Returns the serving status of the service, an empty service name asks for the whole server.
+    async fn check(service: String) -> ServingStatus;
+}
+
+/// Serving status of a service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServingStatus {
+    /// The service is serving.
+    Serving,
+    /// The service is not serving.
+    NotServing,
+    /// The service is unknown to the server.
+    Unknown,
+}
+
+/// [`HealthService`] keeps the serving status of the services in memory.
+///
+/// The whole server, named by the empty service name, is serving from the start.
+#[derive(Clone)]
+pub struct HealthService {
+    statuses: Arc<RwLock<HashMap<String, ServingStatus>>>,
+}
+
+impl Default for HealthService {
+    fn default() -> Self {
+        Self {
+            statuses: Arc::new(RwLock::new([(String::new(), ServingStatus::Serving)].into_iter().collect())),
+        }
+    }
+}
+
+impl HealthService {
+    /// Creates a new [`HealthService`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the serving status of the service.
+    pub fn set_status(&self, service: impl Into<String>, status: ServingStatus) {
+        self.statuses.write().unwrap().insert(service.into(), status);
+    }
+}
+
+impl Health for HealthService {
+    async fn check(self, _: Context, service: String) -> ServingStatus {
+        self.statuses.read().unwrap().get(&service).copied().unwrap_or(ServingStatus::Unknown)
+    }
+}
+
+/// Connects to the health component listening on the address.
+pub async fn connect(address: impl ToSocketAddrs) -> Result<HealthClient, std::io::Error> {
+    let transport = tcp::connect(address, <HealthService as Health>::TRANSPORT_CODEC.to_fn()).await?;
+    Ok(HealthClient::new(Config::default(), transport).spawn())
+}
+
+/// Connects to the health component listening on the address, which may also be a unix socket address.
+pub(crate) async fn connect_address(address: &Address) -> Result<HealthClient, std::io::Error> {
+    match address {
+        Address::Ip(address) => connect(address).await,
+        #[cfg(target_family = "unix")]
+        Address::Unix(address) => {
+            let framed = LengthDelimitedCodec::builder().new_framed(connect_unix(address).await?);
+            let transport = crate::transport::new(framed, <HealthService as Health>::TRANSPORT_CODEC.to_fn());
+            Ok(HealthClient::new(Config::default(), transport).spawn())
+        },
+    }
+}
@@ -13,12 +13,18 @@
 #![feature(fn_traits)]
 #![feature(impl_trait_in_assoc_type)]
 #![feature(get_mut_unchecked)]
+#![feature(return_type_notation)]
+
+// Allows the components generated inside this crate to refer to `::logimesh`.
+extern crate self as logimesh;
 
 pub use logimesh_macro::{component, derive_serde};
 pub mod client;
 pub mod component;
 pub mod context;
+pub mod health;
 pub mod net;
+pub mod registry;
 pub mod server;
 pub mod transport;
 pub use ::tarpc::{serde, tokio_serde, tokio_util, ChannelError, ClientMessage, Request, RequestName, Response, ServerError};
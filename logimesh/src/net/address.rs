@@ -8,6 +8,8 @@
 
 use std::fmt;
 use std::hash::Hash;
+#[cfg(target_family = "unix")]
+use std::io;
 use std::net::{AddrParseError, Ipv6Addr, SocketAddr};
 #[cfg(target_os = "linux")]
 use std::os::linux::net::SocketAddrExt;
@@ -36,16 +38,16 @@ impl PartialEq for Address {
             (Self::Ip(self_ip), Self::Ip(other_ip)) => self_ip == other_ip,
             #[cfg(target_family = "unix")]
             (Self::Unix(self_uds), Self::Unix(other_uds)) => {
+                #[cfg(target_os = "linux")]
+                match (self_uds.as_abstract_name(), other_uds.as_abstract_name()) {
+                    (Some(self_name), Some(other_name)) => return self_name == other_name,
+                    (None, None) => {},
+                    _ => return false,
+                }
                 match (self_uds.as_pathname(), other_uds.as_pathname()) {
                     (Some(self_pathname), Some(other_pathname)) => self_pathname == other_pathname,
-                    (None, None) => {
-                        // Both uds are unnamed, so they cannot be compared.
-                        //
-                        // We noticed that the `PartialEq`, `Eq` and `Hash` are only used for load
-                        // balance, and load balace can only be used for TCP connection.  So we can
-                        // treat the unnamed uds as the same.
-                        true
-                    },
+                    // Both uds are unnamed, so they cannot be told apart, which agrees with `Hash`.
+                    (None, None) => true,
                     // named and unnamed must be different
                     _ => false,
                 }
@@ -114,7 +116,7 @@ impl fmt::Display for Address {
             Address::Unix(addr) => {
                 #[cfg(target_os = "linux")]
                 if let Some(abs_name) = addr.as_abstract_name() {
-                    return write!(f, "{}", abs_name.escape_ascii());
+                    return write!(f, "@{}", abs_name.escape_ascii());
                 }
                 if let Some(pathname) = addr.as_pathname() {
                     write!(f, "{}", pathname.to_string_lossy())
@@ -148,14 +150,83 @@ impl From<TokioUnixSocketAddr> for Address {
     }
 }
 
+/// An error which can be returned when parsing an [`Address`].
+#[derive(thiserror::Error, Debug)]
+pub enum AddressParseError {
+    /// Invalid IP socket address.
+    #[error(transparent)]
+    Ip(#[from] AddrParseError),
+    /// Invalid unix socket address.
+    #[cfg(target_family = "unix")]
+    #[error("invalid unix socket address {0:?}: {1}")]
+    Unix(String, io::Error),
+}
+
+/// Parses an IP socket address, e.g. `127.0.0.1:8888`, or a unix socket address.
+///
+/// A unix socket address is a path starting with `/` or `.`, optionally prefixed with `unix:`,
+/// or an abstract name prefixed with `@` on linux, e.g. `unix:/tmp/hello.sock` or `@hello`.
 impl FromStr for Address {
-    type Err = AddrParseError;
+    type Err = AddressParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let addr: Result<SocketAddr, AddrParseError> = s.parse();
-        match addr {
-            Ok(addr) => Ok(addr.into()),
-            Err(e) => Err(e),
+        #[cfg(target_family = "unix")]
+        {
+            let uds = s.strip_prefix("unix:").or_else(|| s.starts_with(['/', '.', '@']).then_some(s));
+            if let Some(uds) = uds {
+                return parse_unix(uds).map(Address::Unix).map_err(|e| AddressParseError::Unix(s.to_owned(), e));
+            }
+        }
+        Ok(s.parse::<SocketAddr>()?.into())
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn parse_unix(s: &str) -> io::Result<StdUnixSocketAddr> {
+    #[cfg(target_os = "linux")]
+    if let Some(name) = s.strip_prefix('@') {
+        return StdUnixSocketAddr::from_abstract_name(name);
+    }
+    StdUnixSocketAddr::from_pathname(s)
+}
+
+/// Connects to the unix socket address, which may also be an abstract name on linux.
+#[cfg(target_family = "unix")]
+pub(crate) async fn connect_unix(address: &StdUnixSocketAddr) -> io::Result<tokio::net::UnixStream> {
+    if let Some(pathname) = address.as_pathname() {
+        return tokio::net::UnixStream::connect(pathname).await;
+    }
+    // Connecting a unix socket does not block, so the std connect can be used for the abstract names.
+    let stream = std::os::unix::net::UnixStream::connect_addr(address)?;
+    stream.set_nonblocking(true)?;
+    tokio::net::UnixStream::from_std(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_addresses() {
+        let ip: Address = "127.0.0.1:8888".parse().unwrap();
+        assert_eq!(ip, Address::Ip("127.0.0.1:8888".parse().unwrap()));
+        assert!(matches!("localhost:8888".parse::<Address>(), Err(AddressParseError::Ip(_))));
+
+        #[cfg(target_family = "unix")]
+        {
+            let path: Address = "unix:/tmp/hello.sock".parse().unwrap();
+            assert_eq!(path, "/tmp/hello.sock".parse().unwrap());
+            assert_eq!(path.to_string(), "/tmp/hello.sock");
+            assert!(matches!("unix:/tmp/\0.sock".parse::<Address>(), Err(AddressParseError::Unix(..))));
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let abstract_name: Address = "@hello".parse().unwrap();
+            assert_eq!(abstract_name.to_string(), "@hello");
+            assert_eq!(abstract_name.to_string().parse::<Address>().unwrap(), abstract_name);
+            assert_ne!(abstract_name, "@world".parse().unwrap());
+            assert_ne!(abstract_name, "/tmp/hello.sock".parse().unwrap());
         }
     }
 }
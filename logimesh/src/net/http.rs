@@ -0,0 +1,118 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//! A minimal HTTP/1.1 client, which is enough to talk to the local agents of service registries.
+
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// HTTP response.
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    /// Returns the value of the header, the name is case-insensitive.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// Returns an error if the status is not 2xx.
+    pub(crate) fn error_for_status(self) -> io::Result<Self> {
+        if (200..300).contains(&self.status) {
+            Ok(self)
+        } else {
+            Err(io::Error::other(format!("HTTP status {}: {}", self.status, String::from_utf8_lossy(&self.body))))
+        }
+    }
+}
+
+/// Sends a request over a new connection, `host` is in the format of `host:port`.
+pub(crate) async fn request(host: &str, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> io::Result<Response> {
+    let mut stream = TcpStream::connect(host).await?;
+    let mut head = format!("{method} {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\nContent-Length: {}\r\n", body.len());
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await?;
+    parse_response(&buf)
+}
+
+/// Percent-encodes a path segment or query value.
+pub(crate) fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+fn parse_response(buf: &[u8]) -> io::Result<Response> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid HTTP response: {msg}"));
+    let head_end = buf.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(|| invalid("incomplete head"))?;
+    let head = std::str::from_utf8(&buf[..head_end]).map_err(|_| invalid("non-utf8 head"))?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("bad status line"))?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect::<Vec<_>>();
+    let mut response = Response {
+        status,
+        headers,
+        body: buf[head_end + 4..].to_vec(),
+    };
+    if response.header("Transfer-Encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked")) {
+        response.body = decode_chunked(&response.body).ok_or_else(|| invalid("bad chunked body"))?;
+    } else if let Some(len) = response.header("Content-Length").and_then(|v| v.parse::<usize>().ok()) {
+        response.body.truncate(len);
+    }
+    Ok(response)
+}
+
+fn decode_chunked(mut buf: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = buf.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&buf[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        buf = &buf[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(buf.get(..size)?);
+        buf = buf.get(size + 2..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_chunked_response() {
+        let response = parse_response(b"HTTP/1.1 200 OK\r\nX-Consul-Index: 7\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n[1,\r\n2\r\n2]\r\n0\r\n\r\n").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("x-consul-index"), Some("7"));
+        assert_eq!(response.body, b"[1,2]");
+        assert_eq!(encode("a b/c"), "a%20b%2Fc");
+    }
+}
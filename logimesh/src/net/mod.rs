@@ -5,5 +5,6 @@
 // https://opensource.org/licenses/MIT.
 //! net tool
 pub mod address;
+pub(crate) mod http;
 mod probe;
-pub use address::Address;
+pub use address::{Address, AddressParseError};
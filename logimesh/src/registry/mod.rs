@@ -0,0 +1,265 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! A small built-in service registry, which is itself a logimesh component.
+//!
+//! Servers register their [`Instance`] under a TTL lease and renew it with heartbeats,
+//! and clients find them through [`crate::client::discover::RegistryDiscover`].
+//!
+//! # Example:
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! use logimesh::component::Endpoint;
+//! use logimesh::registry::{Registration, Registry, RegistryLease, RegistryService};
+//! use std::time::Duration;
+//!
+//! // Serve the registry.
+//! tokio::spawn(async {
+//!     logimesh::tokio_tcp_listen!(RegistryService::new(), logimesh::server::TcpConfig::new("127.0.0.1:7777"));
+//! });
+//!
+//! // Register a server instance, the lease is renewed in the background until it is dropped.
+//! let client = logimesh::registry::connect("127.0.0.1:7777").await?;
+//! let registration = Registration::new(&Endpoint::new("hello"), "127.0.0.1:8888".parse()?, 1);
+//! let _lease = RegistryLease::grant(client, registration, Duration::from_secs(10)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod service;
+
+use crate::client::core::{Config, RpcError};
+use crate::client::discover::Instance;
+use crate::component::Endpoint;
+use crate::net::{Address, AddressParseError};
+use crate::server::Registrar;
+use crate::transport::tcp;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::ToSocketAddrs;
+use tokio::task::JoinHandle;
+use tracing::{trace, warn};
+
+pub use service::RegistryService;
+
+/// Default TTL of the leases granted to [`RegistryRegistrar`].
+const DEFAULT_LEASE_TTL: Duration = Duration::from_secs(10);
+
+/// The registry component.
+#[crate::component]
+pub trait Registry {
+    /// Registers the instance under a lease that expires after `ttl_ms` milliseconds without heartbeat,
+    /// and returns the lease id. The in-memory [`RegistryService`] clamps the ttl to one hour.
+    ///
+    /// A previous registration of the same endpoint key and address is replaced.
+    async fn register(registration: Registration, ttl_ms: u64) -> u64;
+    /// Renews the lease.
+    async fn heartbeat(lease_id: u64) -> Result<(), RegistryError>;
+    /// Revokes the lease and removes its instance, returns false if the lease does not exist.
+    async fn deregister(lease_id: u64) -> bool;
+    /// Returns the instances registered under the endpoint key.
+    ///
+    /// When `revision` equals the current revision of the key, waits up to `wait_ms` milliseconds for a change.
+    /// The in-memory [`RegistryService`] clamps the wait to five minutes.
+    async fn watch(key: String, revision: u64, wait_ms: u64) -> Snapshot;
+}
+
+/// An instance registered under an endpoint key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registration {
+    /// Endpoint key, see [`Endpoint::key`].
+    pub key: String,
+    /// Service name of the endpoint.
+    pub service_name: String,
+    /// Instance address.
+    pub address: String,
+    /// Instance weight.
+    pub weight: u32,
+    /// Instance tags.
+    pub tags: HashMap<String, String>,
+}
+
+impl Registration {
+    /// Creates a new [`Registration`] of the endpoint.
+    pub fn new(endpoint: &Endpoint, address: Address, weight: u32) -> Self {
+        Self {
+            key: endpoint.key().to_string(),
+            service_name: endpoint.service_name().to_string(),
+            address: address.to_string(),
+            weight,
+            tags: HashMap::new(),
+        }
+    }
+
+    /// Set the instance tags.
+    pub fn with_tags(mut self, tags: HashMap<String, String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Returns the registered instance.
+    pub fn to_instance(&self) -> Result<Instance, AddressParseError> {
+        Ok(Instance {
+            address: self.address.parse()?,
+            weight: self.weight,
+            tags: self.tags.iter().map(|(k, v)| (k.clone().into(), v.clone().into())).collect(),
+        })
+    }
+}
+
+/// The instances registered under an endpoint key.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The revision of the endpoint key, which changes whenever the instances change.
+    pub revision: u64,
+    /// The registered instances.
+    pub registrations: Vec<Registration>,
+}
+
+/// Registry errors.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RegistryError {
+    /// The lease has expired or was revoked.
+    #[error("lease {0} not found")]
+    LeaseNotFound(u64),
+}
+
+/// Connects to the registry listening on the address.
+pub async fn connect(address: impl ToSocketAddrs) -> Result<RegistryClient, std::io::Error> {
+    let transport = tcp::connect(address, <RegistryService as Registry>::TRANSPORT_CODEC.to_fn()).await?;
+    Ok(RegistryClient::new(Config::default(), transport).spawn())
+}
+
+/// [`RegistryRegistrar`] registers servers on the registry under renewed leases, see [`crate::server::Registrar`].
+#[derive(Clone)]
+pub struct RegistryRegistrar {
+    client: RegistryClient,
+    ttl: Duration,
+    weight: u32,
+    tags: HashMap<String, String>,
+    advertise_ip: Option<IpAddr>,
+}
+
+impl RegistryRegistrar {
+    /// Creates a new [`RegistryRegistrar`] registering through the client.
+    pub fn new(client: RegistryClient) -> Self {
+        Self {
+            client,
+            ttl: DEFAULT_LEASE_TTL,
+            weight: 1,
+            tags: HashMap::new(),
+            advertise_ip: None,
+        }
+    }
+
+    /// Set the TTL of the leases, default is 10s.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set the weight of the registered instances, default is 1.
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Set the tags of the registered instances.
+    pub fn with_tags(mut self, tags: HashMap<String, String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Set the IP registered in place of the bound one, e.g. when the server listens on `0.0.0.0`.
+    pub fn with_advertise_ip(mut self, ip: IpAddr) -> Self {
+        self.advertise_ip = Some(ip);
+        self
+    }
+}
+
+impl Registrar for RegistryRegistrar {
+    type Registration = RegistryLease;
+
+    fn register<'s>(&'s self, endpoint: &'s Endpoint, address: &'s Address) -> impl Future<Output = anyhow::Result<RegistryLease>> + Send {
+        async move {
+            let address = match (address, self.advertise_ip) {
+                (Address::Ip(address), Some(ip)) => Address::Ip(SocketAddr::new(ip, address.port())),
+                (address, _) => address.clone(),
+            };
+            let registration = Registration::new(endpoint, address, self.weight).with_tags(self.tags.clone());
+            Ok(RegistryLease::grant(self.client.clone(), registration, self.ttl).await?)
+        }
+    }
+
+    fn deregister(&self, lease: RegistryLease) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async move {
+            lease.revoke().await?;
+            Ok(())
+        }
+    }
+}
+
+/// A lease granted by the registry, which is renewed in the background until it is revoked or dropped.
+///
+/// When the lease is lost, e.g. after the registry restarts, the instance is registered again.
+pub struct RegistryLease {
+    client: RegistryClient,
+    lease_id: Arc<AtomicU64>,
+    heartbeat: JoinHandle<()>,
+}
+
+impl RegistryLease {
+    /// Registers the instance and keeps its lease alive.
+    pub async fn grant(client: RegistryClient, registration: Registration, ttl: Duration) -> Result<Self, RpcError> {
+        let ttl_ms = ttl.as_millis().max(1) as u64;
+        let lease_id = Arc::new(AtomicU64::new(client.register(crate::context::current(), registration.clone(), ttl_ms).await?));
+        let heartbeat = tokio::spawn(keep_alive(client.clone(), registration, ttl, lease_id.clone()));
+        Ok(Self { client, lease_id, heartbeat })
+    }
+
+    /// Returns the current lease id.
+    pub fn lease_id(&self) -> u64 {
+        self.lease_id.load(Ordering::Acquire)
+    }
+
+    /// Stops the heartbeat and removes the instance from the registry.
+    pub async fn revoke(self) -> Result<bool, RpcError> {
+        self.heartbeat.abort();
+        self.client.deregister(crate::context::current(), self.lease_id()).await
+    }
+}
+
+impl Drop for RegistryLease {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+    }
+}
+
+async fn keep_alive(client: RegistryClient, registration: Registration, ttl: Duration, lease_id: Arc<AtomicU64>) {
+    let mut interval = tokio::time::interval((ttl / 3).max(Duration::from_millis(1)));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let id = lease_id.load(Ordering::Acquire);
+        match client.heartbeat(crate::context::current(), id).await {
+            Ok(Ok(())) => trace!("[LOGIMESH] renewed registry lease {id}"),
+            Ok(Err(RegistryError::LeaseNotFound(_))) => match client.register(crate::context::current(), registration.clone(), ttl.as_millis().max(1) as u64).await {
+                Ok(id) => {
+                    lease_id.store(id, Ordering::Release);
+                    trace!("[LOGIMESH] registered again with registry lease {id}")
+                },
+                Err(e) => warn!("[LOGIMESH] failed to register again: {e:?}"),
+            },
+            Err(e) => warn!("[LOGIMESH] failed to renew registry lease {id}: {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! In-memory registry service.
+
+use super::{Registration, Registry, RegistryError, Snapshot};
+use crate::context::Context;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::trace;
+
+/// The longest lease ttl a registration can request, longer ttls are clamped.
+const MAX_TTL: Duration = Duration::from_secs(60 * 60);
+/// The longest time a watch request can wait for changes, longer waits are clamped.
+const MAX_WAIT: Duration = Duration::from_secs(5 * 60);
+
+/// [`RegistryService`] keeps the registered instances in memory, and expires leases without heartbeat.
+#[derive(Clone, Default)]
+pub struct RegistryService {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: Mutex<State>,
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    next_lease_id: u64,
+    revision: u64,
+    leases: HashMap<u64, Lease>,
+    revisions: HashMap<String, u64>,
+}
+
+struct Lease {
+    registration: Registration,
+    ttl: Duration,
+    expires_at: Instant,
+}
+
+impl RegistryService {
+    /// Creates an empty [`RegistryService`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl State {
+    fn touch(&mut self, key: &str) {
+        self.revision += 1;
+        self.revisions.insert(key.to_string(), self.revision);
+    }
+
+    /// Removes the expired leases, returns whether any lease expired and when the next lease expires.
+    fn expire(&mut self, now: Instant) -> (bool, Option<Instant>) {
+        let expired = self.leases.iter().filter(|(_, lease)| lease.expires_at <= now).map(|(id, _)| *id).collect::<Vec<_>>();
+        for id in &expired {
+            if let Some(lease) = self.leases.remove(id) {
+                trace!("[LOGIMESH] registry lease {id} expired: {:?}", lease.registration);
+                self.touch(&lease.registration.key);
+            }
+        }
+        (!expired.is_empty(), self.leases.values().map(|lease| lease.expires_at).min())
+    }
+
+    fn snapshot(&self, key: &str) -> Snapshot {
+        let mut registrations = self.leases.iter().filter(|(_, lease)| lease.registration.key == key).collect::<Vec<_>>();
+        registrations.sort_by_key(|(id, _)| **id);
+        Snapshot {
+            revision: self.revisions.get(key).copied().unwrap_or_default(),
+            registrations: registrations.into_iter().map(|(_, lease)| lease.registration.clone()).collect(),
+        }
+    }
+}
+
+impl Inner {
+    fn update<R>(&self, f: impl FnOnce(&mut State) -> (bool, R)) -> R {
+        let (changed, r) = {
+            let mut state = self.state.lock().unwrap();
+            let (expired, _) = state.expire(Instant::now());
+            let (changed, r) = f(&mut state);
+            (changed || expired, r)
+        };
+        if changed {
+            self.changed.notify_waiters();
+        }
+        r
+    }
+}
+
+impl Registry for RegistryService {
+    async fn register(self, _: Context, registration: Registration, ttl_ms: u64) -> u64 {
+        self.inner.update(|state| {
+            state
+                .leases
+                .retain(|_, lease| lease.registration.key != registration.key || lease.registration.address != registration.address);
+            state.next_lease_id += 1;
+            let lease_id = state.next_lease_id;
+            let ttl = Duration::from_millis(ttl_ms).min(MAX_TTL);
+            state.touch(&registration.key);
+            state.leases.insert(
+                lease_id,
+                Lease {
+                    registration,
+                    ttl,
+                    expires_at: Instant::now() + ttl,
+                },
+            );
+            (true, lease_id)
+        })
+    }
+
+    async fn heartbeat(self, _: Context, lease_id: u64) -> Result<(), RegistryError> {
+        self.inner.update(|state| match state.leases.get_mut(&lease_id) {
+            Some(lease) => {
+                lease.expires_at = Instant::now() + lease.ttl;
+                (false, Ok(()))
+            },
+            None => (false, Err(RegistryError::LeaseNotFound(lease_id))),
+        })
+    }
+
+    async fn deregister(self, _: Context, lease_id: u64) -> bool {
+        self.inner.update(|state| match state.leases.remove(&lease_id) {
+            Some(lease) => {
+                state.touch(&lease.registration.key);
+                (true, true)
+            },
+            None => (false, false),
+        })
+    }
+
+    async fn watch(self, _: Context, key: String, revision: u64, wait_ms: u64) -> Snapshot {
+        let deadline = Instant::now() + Duration::from_millis(wait_ms).min(MAX_WAIT);
+        loop {
+            // Register for notifications before reading the state, so that no change is missed.
+            let changed = self.inner.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            let (snapshot, next_expiry) = {
+                let mut state = self.inner.state.lock().unwrap();
+                let (expired, next_expiry) = state.expire(Instant::now());
+                if expired {
+                    self.inner.changed.notify_waiters();
+                }
+                (state.snapshot(&key), next_expiry)
+            };
+            if snapshot.revision != revision || Instant::now() >= deadline {
+                return snapshot;
+            }
+            let wake_at = next_expiry.map_or(deadline, |expiry| expiry.min(deadline));
+            tokio::select! {
+                _ = changed => {},
+                _ = tokio::time::sleep_until(wake_at.into()) => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::component::Endpoint;
+
+    #[tokio::test]
+    async fn lease_expires_without_heartbeat() {
+        let registry = RegistryService::new();
+        let registration = Registration::new(&Endpoint::new("hello"), "127.0.0.1:8888".parse().unwrap(), 1);
+        let lease_id = registry.clone().register(crate::context::current(), registration.clone(), 50).await;
+
+        let snapshot = registry.clone().watch(crate::context::current(), "hello".into(), 0, 0).await;
+        assert_eq!(snapshot.registrations, vec![registration]);
+
+        // The long poll returns as soon as the lease expires.
+        let snapshot = registry.clone().watch(crate::context::current(), "hello".into(), snapshot.revision, 5_000).await;
+        assert!(snapshot.registrations.is_empty());
+        assert_eq!(registry.heartbeat(crate::context::current(), lease_id).await, Err(RegistryError::LeaseNotFound(lease_id)));
+    }
+
+    #[tokio::test]
+    async fn clamp_ttl_and_wait() {
+        let registry = RegistryService::new();
+        let registration = Registration::new(&Endpoint::new("hello"), "127.0.0.1:8888".parse().unwrap(), 1);
+        let lease_id = registry.clone().register(crate::context::current(), registration.clone(), u64::MAX).await;
+        assert_eq!(registry.clone().heartbeat(crate::context::current(), lease_id).await, Ok(()));
+
+        let snapshot = registry.clone().watch(crate::context::current(), "hello".into(), 0, u64::MAX).await;
+        assert_eq!(snapshot.registrations, vec![registration]);
+        // The long poll waits for a change, so a deregistration ends it.
+        let watch = tokio::spawn(registry.clone().watch(crate::context::current(), "hello".into(), snapshot.revision, u64::MAX));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(registry.deregister(crate::context::current(), lease_id).await);
+        assert!(watch.await.unwrap().registrations.is_empty());
+    }
+}
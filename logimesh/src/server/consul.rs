@@ -0,0 +1,157 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! Server registration on a Consul agent.
+
+use super::Registrar;
+use crate::client::discover::{consul_tags, ConsulAgent};
+use crate::component::Endpoint;
+use crate::net::Address;
+use faststr::FastStr;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// Default interval of the TCP health check run by the Consul agent.
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// Default time after which Consul removes an instance whose health check keeps failing.
+const DEFAULT_DEREGISTER_AFTER: Duration = Duration::from_secs(60);
+
+/// [`ConsulRegistrar`] registers servers on a Consul agent, so that [`crate::client::discover::ConsulDiscover`] can find them.
+///
+/// The [`crate::client::discover::ConsulTags`] tag of the endpoint becomes the Consul service tags,
+/// and the agent checks the registered address with a TCP health check.
+///
+/// # Example:
+/// ```no_run
+/// # async fn run() {
+/// use logimesh::component::Endpoint;
+/// use logimesh::registry::{Registry, RegistryService};
+/// use logimesh::server::{ConsulRegistrar, TcpConfig};
+///
+/// let registrar = ConsulRegistrar::new("127.0.0.1:8500");
+/// logimesh::tokio_tcp_listen!(RegistryService::new(), TcpConfig::new("0.0.0.0:0"), registrar, Endpoint::new("registry"));
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConsulRegistrar {
+    agent: ConsulAgent,
+    weight: u32,
+    meta: HashMap<String, String>,
+    check_interval: Duration,
+    deregister_after: Duration,
+}
+
+/// A service instance registered by [`ConsulRegistrar`].
+///
+/// Dropping it does not deregister the instance, Consul removes it after its health check keeps failing,
+/// unless it is held by a [`super::RegistrationGuard`].
+#[derive(Debug)]
+#[must_use = "the instance stays registered until it is deregistered"]
+pub struct ConsulRegistration {
+    agent: ConsulAgent,
+    id: String,
+}
+
+impl ConsulRegistrar {
+    /// Creates a new [`ConsulRegistrar`] with the HTTP address of the Consul agent, e.g. `127.0.0.1:8500`.
+    pub fn new(agent_address: impl Into<FastStr>) -> Self {
+        Self {
+            agent: ConsulAgent::new(agent_address),
+            weight: 1,
+            meta: HashMap::new(),
+            check_interval: DEFAULT_CHECK_INTERVAL,
+            deregister_after: DEFAULT_DEREGISTER_AFTER,
+        }
+    }
+
+    /// Set the ACL token sent with every request.
+    pub fn with_token(mut self, token: impl Into<FastStr>) -> Self {
+        self.agent.set_token(token);
+        self
+    }
+
+    /// Set the weight of the instances when they are passing, default is 1.
+    pub fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Set the Consul service meta, which becomes the [`crate::client::discover::Instance::tags`].
+    pub fn with_meta(mut self, meta: HashMap<String, String>) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    /// Set the interval of the TCP health check, default is 10s.
+    pub fn with_check_interval(mut self, check_interval: Duration) -> Self {
+        self.check_interval = check_interval;
+        self
+    }
+
+    /// Set the time after which Consul removes an instance whose health check keeps failing, default is 1m.
+    pub fn with_deregister_after(mut self, deregister_after: Duration) -> Self {
+        self.deregister_after = deregister_after;
+        self
+    }
+}
+
+impl Registrar for ConsulRegistrar {
+    type Registration = ConsulRegistration;
+
+    /// Registers the instance of the endpoint listening on the address.
+    ///
+    /// An unspecified IP, e.g. `0.0.0.0`, is registered as the address of the Consul node.
+    fn register<'s>(&'s self, endpoint: &'s Endpoint, address: &'s Address) -> impl Future<Output = anyhow::Result<ConsulRegistration>> + Send {
+        async move {
+            let Address::Ip(address) = address else {
+                anyhow::bail!("consul can not register the address {address}")
+            };
+            let id = format!("{}-{address}", endpoint.service_name_ref());
+            // The agent runs on the same node, so it reaches an unspecified address through the loopback.
+            let (service_address, check_ip) = match address.ip() {
+                IpAddr::V4(ip) if ip.is_unspecified() => (String::new(), IpAddr::V4(Ipv4Addr::LOCALHOST)),
+                IpAddr::V6(ip) if ip.is_unspecified() => (String::new(), IpAddr::V6(Ipv6Addr::LOCALHOST)),
+                ip => (ip.to_string(), ip),
+            };
+            let service = serde_json::json!({
+                "ID": id,
+                "Name": endpoint.service_name_ref(),
+                "Tags": consul_tags(endpoint),
+                "Address": service_address,
+                "Port": address.port(),
+                "Meta": self.meta,
+                "Weights": { "Passing": self.weight, "Warning": 1 },
+                "Check": {
+                    "TCP": SocketAddr::new(check_ip, address.port()).to_string(),
+                    "Interval": format!("{}ms", self.check_interval.as_millis()),
+                    "DeregisterCriticalServiceAfter": format!("{}ms", self.deregister_after.as_millis()),
+                },
+            });
+            self.agent.request("PUT", "/v1/agent/service/register", service.to_string().as_bytes()).await?;
+            Ok(ConsulRegistration { agent: self.agent.clone(), id })
+        }
+    }
+
+    fn deregister(&self, registration: ConsulRegistration) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async move { Ok(registration.deregister().await?) }
+    }
+}
+
+impl ConsulRegistration {
+    /// Returns the Consul service ID.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Removes the instance from Consul.
+    pub async fn deregister(self) -> io::Result<()> {
+        let path = format!("/v1/agent/service/deregister/{}", crate::net::http::encode(&self.id));
+        self.agent.request("PUT", &path, &[]).await.map(|_| ())
+    }
+}
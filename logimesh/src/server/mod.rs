@@ -7,6 +7,14 @@
 
 use tokio::net::ToSocketAddrs;
 
+mod consul;
+mod registrar;
+#[cfg(target_family = "unix")]
+mod unix;
+pub use consul::{ConsulRegistrar, ConsulRegistration};
+pub use registrar::{Registrar, RegistrationGuard};
+#[cfg(target_family = "unix")]
+pub use unix::{UnixConfig, UnixSocketFile};
 pub use core::*;
 
 mod core {
@@ -108,6 +116,10 @@ impl<A: ToSocketAddrs> TcpConfig<A> {
 }
 
 /// Listen a TCP server.
+///
+/// When a [`Registrar`] and the [`crate::component::Endpoint`] of the component are given, the bound address,
+/// including the port picked for a port 0 bind, is registered after binding, and deregistered when the server stops.
+/// When the registration fails, the error is logged and the server is served unregistered.
 /// # Example:
 /// ```
 /// extern crate tokio;
@@ -122,15 +134,48 @@ impl<A: ToSocketAddrs> TcpConfig<A> {
 /// ```
 #[macro_export]
 macro_rules! tokio_tcp_listen {
+    (@bind $component:expr, $tcp_config:expr) => {{
+        let mut listener = ::logimesh::transport::tcp::listen($tcp_config.listen_address(), $component.__logimesh_codec().to_fn()).await.unwrap();
+        ::logimesh::tracing::info!("[LOGIMESH] Listening on {}", listener.local_addr());
+        listener.config_mut().max_frame_length($tcp_config.max_frame_len());
+        listener
+    }};
     ($component:expr, $tcp_config:expr $(,)?) => {{
+        let (component, tcp_config) = ($component, $tcp_config);
+        let listener = ::logimesh::tokio_tcp_listen!(@bind component, tcp_config);
+        ::logimesh::__logimesh_serve!(listener, component, tcp_config, |t| t.transport().peer_addr().unwrap().ip());
+    }};
+    ($component:expr, $tcp_config:expr, $registrar:expr, $endpoint:expr $(,)?) => {{
+        let (component, tcp_config) = ($component, $tcp_config);
+        let listener = ::logimesh::tokio_tcp_listen!(@bind component, tcp_config);
+        // The guard also deregisters when the serving future is dropped.
+        let registration = match ::logimesh::server::RegistrationGuard::register($registrar, &$endpoint, listener.local_addr().into()).await {
+            ::core::result::Result::Ok(registration) => ::core::option::Option::Some(registration),
+            ::core::result::Result::Err(err) => {
+                ::logimesh::tracing::error!("[LOGIMESH] failed to register the server, serving unregistered: {err:?}");
+                ::core::option::Option::None
+            },
+        };
+        ::logimesh::__logimesh_serve!(listener, component, tcp_config, |t| t.transport().peer_addr().unwrap().ip());
+        if let ::core::option::Option::Some(registration) = registration {
+            if let ::core::result::Result::Err(err) = registration.deregister().await {
+                ::logimesh::tracing::warn!("[LOGIMESH] failed to deregister the server: {err:?}");
+            }
+        }
+    }};
+}
+
+/// Serves the component on the incoming transports of a listener, the key function maps a channel to the key of its
+/// `max_channels_per_key` limit.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __logimesh_serve {
+    ($listener:expr, $component:expr, $config:expr, $channel_key:expr) => {{
         use ::logimesh::futures::prelude::*;
         use ::logimesh::server::incoming::Incoming as _;
         use ::logimesh::server::Channel as _;
         let serve = $component.logimesh_serve();
-        let mut listener = ::logimesh::transport::tcp::listen($tcp_config.listen_address(), $component.__logimesh_codec().to_fn()).await.unwrap();
-        ::logimesh::tracing::info!("[LOGIMESH] Listening on {}", listener.local_addr());
-        listener.config_mut().max_frame_length($tcp_config.max_frame_len());
-        listener
+        $listener
             // Ignore accept errors.
             .filter_map(|r| {
                 future::ready(r.map_or_else(
@@ -144,13 +189,13 @@ macro_rules! tokio_tcp_listen {
             .map(|transport| {
                 ::logimesh::server::BaseChannel::new(
                     ::logimesh::server::Config {
-                        pending_response_buffer: $tcp_config.pending_response_buffer(),
+                        pending_response_buffer: $config.pending_response_buffer(),
                     },
                     transport,
                 )
             })
-            // Limit channels to `tcp_config.max_channels_per_key` per IP.
-            .max_channels_per_key($tcp_config.max_channels_per_key(), |t| t.transport().peer_addr().unwrap().ip())
+            // Limit channels to `config.max_channels_per_key` per key.
+            .max_channels_per_key($config.max_channels_per_key(), $channel_key)
             // serve is generated by the component attribute. It takes as input any type implementing
             // the generated World trait.
             .map(|channel| {
@@ -158,8 +203,8 @@ macro_rules! tokio_tcp_listen {
                     ::tokio::spawn(fut);
                 })
             })
-            // Max `tcp_config.buffer_unordered` channels.
-            .buffer_unordered($tcp_config.buffer_unordered())
+            // Max `config.buffer_unordered` channels.
+            .buffer_unordered($config.buffer_unordered())
             .for_each(|_| async {})
             .await;
     }};
@@ -0,0 +1,172 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! Server self-registration.
+
+use crate::component::Endpoint;
+use crate::net::Address;
+use std::future::Future;
+use tracing::{info, warn};
+
+/// [`Registrar`] tells a service registry where a server listens, so that the discovers of the clients can find it.
+///
+/// The listen macros, e.g. [`crate::tokio_tcp_listen!`], register the bound address after binding,
+/// and deregister it when the server stops.
+pub trait Registrar: Clone + Send + Sync + 'static {
+    /// The registered instance.
+    type Registration: Send + 'static;
+
+    /// Registers the instance of the endpoint listening on the address.
+    fn register<'s>(&'s self, endpoint: &'s Endpoint, address: &'s Address) -> impl Future<Output = anyhow::Result<Self::Registration>> + Send;
+
+    /// Removes the registered instance.
+    fn deregister(&self, registration: Self::Registration) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// [`RegistrationGuard`] deregisters the instance when it is dropped, e.g. when the serving future is cancelled.
+#[must_use = "the instance is deregistered when the guard is dropped"]
+pub struct RegistrationGuard<R: Registrar> {
+    registrar: R,
+    address: Address,
+    registration: Option<R::Registration>,
+}
+
+impl<R: Registrar> RegistrationGuard<R> {
+    /// Registers the instance of the endpoint listening on the address.
+    pub async fn register(registrar: R, endpoint: &Endpoint, address: Address) -> anyhow::Result<Self> {
+        let registration = registrar.register(endpoint, &address).await?;
+        info!("[LOGIMESH] Registered {} on {address}", endpoint.service_name_ref());
+        Ok(Self {
+            registrar,
+            address,
+            registration: Some(registration),
+        })
+    }
+
+    /// Removes the registered instance.
+    pub async fn deregister(mut self) -> anyhow::Result<()> {
+        let registration = self.registration.take().expect("registration is only taken once");
+        self.registrar.deregister(registration).await?;
+        info!("[LOGIMESH] Deregistered {}", self.address);
+        Ok(())
+    }
+}
+
+impl<R: Registrar> Drop for RegistrationGuard<R> {
+    fn drop(&mut self) {
+        let Some(registration) = self.registration.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            warn!("[LOGIMESH] {} can not be deregistered outside of a tokio runtime", self.address);
+            return;
+        };
+        let registrar = self.registrar.clone();
+        let address = self.address.clone();
+        runtime.spawn(async move {
+            match registrar.deregister(registration).await {
+                Ok(()) => info!("[LOGIMESH] Deregistered {address}"),
+                Err(e) => warn!("[LOGIMESH] failed to deregister {address}: {e:?}"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::{Health, HealthService, ServingStatus};
+    use crate::server::TcpConfig;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Clone, Default)]
+    struct MemoryRegistrar {
+        registered: Arc<Mutex<Vec<(String, Address)>>>,
+    }
+
+    impl Registrar for MemoryRegistrar {
+        type Registration = Address;
+
+        async fn register(&self, endpoint: &Endpoint, address: &Address) -> anyhow::Result<Address> {
+            self.registered.lock().unwrap().push((endpoint.service_name().to_string(), address.clone()));
+            Ok(address.clone())
+        }
+
+        async fn deregister(&self, address: Address) -> anyhow::Result<()> {
+            self.registered.lock().unwrap().retain(|(_, registered)| *registered != address);
+            Ok(())
+        }
+    }
+
+    #[derive(Clone)]
+    struct FailingRegistrar;
+
+    impl Registrar for FailingRegistrar {
+        type Registration = ();
+
+        async fn register(&self, _: &Endpoint, _: &Address) -> anyhow::Result<()> {
+            anyhow::bail!("registry is down")
+        }
+
+        async fn deregister(&self, _: ()) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn register_bound_address() {
+        let registrar = MemoryRegistrar::default();
+        let server = tokio::spawn({
+            let registrar = registrar.clone();
+            async move {
+                crate::tokio_tcp_listen!(HealthService::new(), TcpConfig::new("127.0.0.1:0"), registrar, Endpoint::new("health"));
+            }
+        });
+        let address = loop {
+            if let Some((service_name, address)) = registrar.registered.lock().unwrap().first().cloned() {
+                assert_eq!(service_name, "health");
+                break address;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let Address::Ip(address) = address else { panic!("unexpected address {address}") };
+        assert_ne!(address.port(), 0);
+        let client = crate::health::connect(address).await.unwrap();
+        assert_eq!(client.check(crate::context::current(), String::new()).await.unwrap(), ServingStatus::Serving);
+
+        // Stopping the server deregisters it.
+        server.abort();
+        let _ = server.await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !registrar.registered.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn serve_unregistered() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            crate::tokio_tcp_listen!(HealthService::new(), TcpConfig::new(address), FailingRegistrar, Endpoint::new("health"));
+        });
+        let client = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(client) = crate::health::connect(address).await {
+                    break client;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(client.check(crate::context::current(), String::new()).await.unwrap(), ServingStatus::Serving);
+        server.abort();
+    }
+}
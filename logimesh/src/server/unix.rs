@@ -0,0 +1,322 @@
+// Copyright Andeya Lee 2024
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+//!
+//! Unix domain socket server.
+
+use super::Config;
+use crate::net::Address;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use tokio::net::UnixListener;
+
+/// Unix domain socket server config.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct UnixConfig {
+    /// listen address, a path of the socket file, or an abstract name prefixed with `@` on linux.
+    pub(crate) listen_address: PathBuf,
+    /// Maximum frame length, default is usize::MAX.
+    pub(crate) max_frame_len: usize,
+    /// Controls the buffer size of the in-process channel over which a server's handlers send
+    /// responses to the [`super::Channel`]. Default is 100.
+    pub(crate) pending_response_buffer: usize,
+    /// Enforces channel per-key limits, the key is the user id of the peer process.
+    pub(crate) max_channels_per_key: u32,
+    /// An adaptor for creating a buffered list of pending futures (unordered).
+    /// Default is 10, and zero means 10.
+    pub(crate) buffer_unordered: usize,
+    /// Permissions of the socket file, e.g. `0o660`.
+    pub(crate) permissions: Option<u32>,
+    /// Whether a socket file left behind by a previous server is removed before binding, default is true.
+    pub(crate) remove_stale: bool,
+}
+
+impl UnixConfig {
+    /// Create a new unix domain socket config.
+    ///
+    /// The listen address is a path of the socket file, or an abstract name prefixed with `@` on linux, e.g. `@hello`.
+    pub fn new(listen_address: impl Into<PathBuf>) -> Self {
+        let server_config = Config::default();
+        Self {
+            listen_address: listen_address.into(),
+            max_frame_len: usize::MAX,
+            pending_response_buffer: server_config.pending_response_buffer,
+            max_channels_per_key: Default::default(),
+            buffer_unordered: 10,
+            permissions: None,
+            remove_stale: true,
+        }
+    }
+    /// listen address.
+    pub fn listen_address(&self) -> &Path {
+        &self.listen_address
+    }
+    /// Set maximum frame length, default is usize::MAX.
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self {
+        if max_frame_len == 0 {
+            self.max_frame_len = usize::MAX;
+        } else {
+            self.max_frame_len = max_frame_len;
+        }
+        self
+    }
+    /// Maximum frame length, default is usize::MAX.
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+    /// Set the buffer size of the in-process channel over which a server's handlers send
+    /// responses to the [`super::Channel`]. Default is 100.
+    pub fn with_pending_response_buffer(mut self, pending_response_buffer: usize) -> Self {
+        if pending_response_buffer == 0 {
+            self.pending_response_buffer = usize::MAX;
+        } else {
+            self.pending_response_buffer = pending_response_buffer;
+        }
+        self
+    }
+    /// The buffer size of the in-process channel over which a server's handlers send
+    /// responses to the [`super::Channel`]. Default is 100.
+    pub fn pending_response_buffer(&self) -> usize {
+        self.pending_response_buffer
+    }
+    /// Set up enforces channel per-key limits, the key is the user id of the peer process.
+    pub fn with_max_channels_per_key(mut self, max_channels_per_key: u32) -> Self {
+        self.max_channels_per_key = max_channels_per_key;
+        self
+    }
+    /// Enforces channel per-key limits, the key is the user id of the peer process.
+    pub fn max_channels_per_key(&self) -> u32 {
+        self.max_channels_per_key
+    }
+    /// Set an adaptor for creating a buffered list of pending futures (unordered).
+    /// Default is 10, and zero means 10.
+    pub fn with_buffer_unordered(mut self, buffer_unordered: usize) -> Self {
+        if buffer_unordered == 0 {
+            self.buffer_unordered = 10;
+        } else {
+            self.buffer_unordered = buffer_unordered;
+        }
+        self
+    }
+    /// An adaptor for creating a buffered list of pending futures (unordered).
+    /// Default is 10, and zero means 10.
+    pub fn buffer_unordered(&self) -> usize {
+        self.buffer_unordered
+    }
+    /// Set the permissions of the socket file, e.g. `0o660`. Abstract names have no file.
+    ///
+    /// The socket is bound in a private directory next to the path and linked to the path once its permissions are set,
+    /// so it can not be connected with the default permissions in between.
+    pub fn with_permissions(mut self, mode: u32) -> Self {
+        self.permissions = Some(mode);
+        self
+    }
+    /// Permissions of the socket file.
+    pub fn permissions(&self) -> Option<u32> {
+        self.permissions
+    }
+    /// Set whether a socket file left behind by a previous server is removed before binding, default is true.
+    ///
+    /// A socket file that still accepts connections is never removed.
+    pub fn with_remove_stale(mut self, remove_stale: bool) -> Self {
+        self.remove_stale = remove_stale;
+        self
+    }
+    /// Whether a socket file left behind by a previous server is removed before binding.
+    pub fn remove_stale(&self) -> bool {
+        self.remove_stale
+    }
+
+    /// Binds the listener, the returned [`UnixSocketFile`] removes the socket file when dropped.
+    pub fn bind(&self) -> io::Result<(UnixListener, UnixSocketFile)> {
+        #[cfg(target_os = "linux")]
+        if let Some(name) = self.listen_address.as_os_str().as_bytes().strip_prefix(b"@") {
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            let listener = std::os::unix::net::UnixListener::bind_addr(&address)?;
+            listener.set_nonblocking(true)?;
+            return Ok((UnixListener::from_std(listener)?, UnixSocketFile { path: None }));
+        }
+        if self.remove_stale {
+            remove_stale_socket(&self.listen_address)?;
+        }
+        let listener = match self.permissions {
+            Some(mode) => bind_with_permissions(&self.listen_address, mode)?,
+            None => UnixListener::bind(&self.listen_address)?,
+        };
+        let socket_file = UnixSocketFile {
+            path: Some(self.listen_address.clone()),
+        };
+        Ok((listener, socket_file))
+    }
+}
+
+/// Removes the socket file when the server stops.
+#[derive(Debug)]
+pub struct UnixSocketFile {
+    path: Option<PathBuf>,
+}
+
+impl UnixSocketFile {
+    /// Returns the address of the socket file, `None` for an abstract name.
+    pub fn address(&self) -> Option<Address> {
+        let path = self.path.as_ref()?;
+        std::os::unix::net::SocketAddr::from_pathname(path).ok().map(Address::Unix)
+    }
+}
+
+impl Drop for UnixSocketFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Binds the socket file in a private directory next to the path, so that it can not be connected before its
+/// permissions are set, and then links it to the path, which fails if the path exists.
+fn bind_with_permissions(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let file_name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file path", path.display())))?;
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(file_name);
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(dir_name);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let private_path = dir.join(file_name);
+    let listener = UnixListener::bind(&private_path).and_then(|listener| {
+        fs::set_permissions(&private_path, fs::Permissions::from_mode(mode))?;
+        fs::hard_link(&private_path, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&private_path);
+    let _ = fs::remove_dir(&dir);
+    listener
+}
+
+/// Removes the socket file at the path unless a server still listens on it.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not a socket", path.display()))),
+        Ok(_) if std::os::unix::net::UnixStream::connect(path).is_ok() => Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use", path.display()))),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Listen a unix domain socket server.
+///
+/// Like [`crate::tokio_tcp_listen!`], a [`super::Registrar`] and the [`crate::component::Endpoint`] of the component
+/// can be given to register the bound address.
+/// # Example:
+/// ```no_run
+/// # async fn run() {
+/// use logimesh::health::{Health, HealthService};
+/// use logimesh::server::UnixConfig;
+///
+/// logimesh::tokio_unix_listen!(HealthService::new(), UnixConfig::new("/tmp/health.sock").with_permissions(0o660));
+/// # }
+/// ```
+#[macro_export]
+macro_rules! tokio_unix_listen {
+    (@bind $component:expr, $unix_config:expr) => {{
+        let (listener, socket_file) = $unix_config.bind().unwrap();
+        let mut listener = ::logimesh::transport::unix::listen_on(listener, $component.__logimesh_codec().to_fn()).await.unwrap();
+        // The socket file may have been bound at a private path first, see `UnixConfig::with_permissions`.
+        let address = socket_file.address().unwrap_or_else(|| ::logimesh::net::Address::from(listener.local_addr().clone()));
+        ::logimesh::tracing::info!("[LOGIMESH] Listening on {address}");
+        listener.config_mut().max_frame_length($unix_config.max_frame_len());
+        (listener, socket_file, address)
+    }};
+    ($component:expr, $unix_config:expr $(,)?) => {{
+        let (component, unix_config) = ($component, $unix_config);
+        let (listener, _socket_file, _) = ::logimesh::tokio_unix_listen!(@bind component, unix_config);
+        ::logimesh::__logimesh_serve!(listener, component, unix_config, |t| t.transport().get_ref().peer_cred().map_or(u32::MAX, |cred| cred.uid()));
+    }};
+    ($component:expr, $unix_config:expr, $registrar:expr, $endpoint:expr $(,)?) => {{
+        let (component, unix_config) = ($component, $unix_config);
+        let (listener, _socket_file, address) = ::logimesh::tokio_unix_listen!(@bind component, unix_config);
+        // The guard also deregisters when the serving future is dropped.
+        let registration = match ::logimesh::server::RegistrationGuard::register($registrar, &$endpoint, address).await {
+            ::core::result::Result::Ok(registration) => ::core::option::Option::Some(registration),
+            ::core::result::Result::Err(err) => {
+                ::logimesh::tracing::error!("[LOGIMESH] failed to register the server, serving unregistered: {err:?}");
+                ::core::option::Option::None
+            },
+        };
+        ::logimesh::__logimesh_serve!(listener, component, unix_config, |t| t.transport().get_ref().peer_cred().map_or(u32::MAX, |cred| cred.uid()));
+        if let ::core::option::Option::Some(registration) = registration {
+            if let ::core::result::Result::Err(err) = registration.deregister().await {
+                ::logimesh::tracing::warn!("[LOGIMESH] failed to deregister the server: {err:?}");
+            }
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::{Health, HealthService, ServingStatus};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn serve_and_clean_up() {
+        let path = std::env::temp_dir().join(format!("logimesh-unix-{}.sock", std::process::id()));
+        // A socket file left behind by a crashed server.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let server = tokio::spawn({
+            let path = path.clone();
+            async move {
+                crate::tokio_unix_listen!(HealthService::new(), UnixConfig::new(path).with_permissions(0o600));
+            }
+        });
+        let instance = crate::client::discover::Instance {
+            address: format!("unix:{}", path.display()).parse().unwrap(),
+            weight: 1,
+            tags: Default::default(),
+        };
+        let client = loop {
+            if let Ok(client) = crate::health::connect_address(&instance.address).await {
+                break client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(client.check(crate::context::current(), String::new()).await.unwrap(), ServingStatus::Serving);
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        // A socket in use is not replaced.
+        assert_eq!(UnixConfig::new(&path).bind().unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        let bind = UnixConfig::new(&path).with_remove_stale(false).with_permissions(0o600).bind();
+        assert_eq!(bind.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        server.abort();
+        let _ = server.await;
+        assert!(!path.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn serve_abstract_name() {
+        let name = format!("@logimesh-unix-{}", std::process::id());
+        tokio::spawn({
+            let name = name.clone();
+            async move {
+                crate::tokio_unix_listen!(HealthService::new(), UnixConfig::new(name));
+            }
+        });
+        let address = name.parse().unwrap();
+        let client = loop {
+            if let Ok(client) = crate::health::connect_address(&address).await {
+                break client;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(client.check(crate::context::current(), String::new()).await.unwrap(), ServingStatus::Serving);
+    }
+}